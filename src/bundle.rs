@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    fs::{remove_file, rename, File, OpenOptions},
+    io::{self, Read},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tar::{Archive, EntryType, Header};
+
+use crate::{
+    cmd::StrResult,
+    image::{digest, LabConfig},
+    manifest, signature, validate,
+};
+
+// a bundle is a plain tar with `bundle.toml` first and the config second, so both are known
+// before the image and its layers are unpacked and checked against the recorded digests.
+
+const FORMAT: u32 = 1;
+const METADATA: &str = "bundle.toml";
+const CONFIG: &str = "config.toml";
+const IMAGE: &str = "image.tar";
+const SIGNATURE: &str = "image.tar.sig";
+
+#[derive(Serialize, Deserialize)]
+pub struct Metadata {
+    pub format: u32,
+    pub name: String,
+    pub created: u64,
+    pub laboratory: String,
+    pub image: Member,
+    #[serde(default)]
+    pub layers: Vec<Member>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Member {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+pub struct Bundle {
+    pub path: String,
+    pub metadata: Metadata,
+    config: String,
+}
+
+impl Bundle {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mut archive = Archive::new(File::open(path).str_result()?);
+        let mut entries = archive.entries().str_result()?;
+
+        let mut next = |name: &str| -> Result<String, String> {
+            let mut entry = match entries.next() {
+                Some(entry) => entry.str_result()?,
+                None => return Err("Bundle is truncated!".to_string()),
+            };
+
+            if !manifest::normalize(&entry.path().str_result()?).eq(name) {
+                return Err(format!("Not a bundle, {} must come first!", name));
+            }
+
+            let mut source = String::new();
+            entry.read_to_string(&mut source).str_result()?;
+
+            Ok(source)
+        };
+
+        let metadata: Metadata = toml::from_str(&next(METADATA)?).str_result()?;
+
+        if metadata.format > FORMAT {
+            return Err(format!(
+                "Bundle format {} is newer than this laboratory supports!",
+                metadata.format
+            ));
+        }
+
+        let config = next(CONFIG)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            metadata,
+            config,
+        })
+    }
+
+    // the bundled config goes through the same checks as one read from a file
+    pub fn config(&self) -> Result<LabConfig, String> {
+        validate::check(&format!("{}:{}", self.path, CONFIG), &self.config)
+            .map_err(|problems| problems.join("\n"))
+    }
+
+    // the image lands at `image_path`, layers next to it; returns the layer paths bottom first
+    pub fn unpack(&self, image_path: &str) -> Result<Vec<String>, String> {
+        let mut destinations: HashMap<&str, (String, Option<&Member>)> = HashMap::new();
+
+        destinations.insert(
+            &self.metadata.image.path,
+            (image_path.to_string(), Some(&self.metadata.image)),
+        );
+        destinations.insert(SIGNATURE, (signature::signature_path(image_path), None));
+
+        let mut layers = Vec::new();
+
+        let signatures: Vec<String> = self
+            .metadata
+            .layers
+            .iter()
+            .map(|member| signature::signature_path(&member.path))
+            .collect();
+
+        for (i, (member, signature)) in self.metadata.layers.iter().zip(&signatures).enumerate() {
+            let layer = format!("{}.layer{}", image_path, i);
+
+            destinations.insert(&member.path, (layer.clone(), Some(member)));
+            destinations.insert(signature, (signature::signature_path(&layer), None));
+            layers.push(layer);
+        }
+
+        for (destination, _) in destinations.values() {
+            if Path::new(destination).exists() {
+                return Err(format!("{}: already exists!", destination));
+            }
+        }
+
+        let mut unpacked: Vec<&str> = Vec::new();
+
+        let result = self.unpack_members(&destinations, &mut unpacked);
+
+        // nothing half-imported is left behind
+        if result.is_err() {
+            for destination in &unpacked {
+                remove_file(destination).ok();
+            }
+        }
+
+        result.map(|_| layers)
+    }
+
+    fn unpack_members<'a>(
+        &self,
+        destinations: &'a HashMap<&str, (String, Option<&Member>)>,
+        unpacked: &mut Vec<&'a str>,
+    ) -> Result<(), String> {
+        let mut archive = Archive::new(File::open(&self.path).str_result()?);
+
+        for entry in archive.entries().str_result()? {
+            let mut entry = entry.str_result()?;
+            let path = manifest::normalize(&entry.path().str_result()?);
+
+            if let Some((destination, _)) = destinations.get(path.as_str()) {
+                // only the data is taken, links and devices have no place in a bundle
+                if !entry.header().entry_type().is_file() {
+                    return Err(format!("{}: not a regular file!", path));
+                }
+
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(destination)
+                    .str_result()?;
+
+                unpacked.push(destination);
+
+                io::copy(&mut entry, &mut file).str_result()?;
+                file.sync_all().str_result()?;
+            }
+        }
+
+        for (path, (destination, member)) in destinations {
+            let member = match member {
+                Some(member) => member,
+                None => continue,
+            };
+
+            if !unpacked.contains(&destination.as_str()) {
+                return Err(format!("Bundle is missing {}!", path));
+            }
+
+            let (sha256, size) = digest(destination)?;
+
+            if !sha256.eq(&member.sha256) || size != member.size {
+                return Err(format!("{}: checksum mismatch!", path));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn write(
+    path: &str,
+    name: &str,
+    config: &str,
+    image_path: &str,
+    layers: &[String],
+) -> Result<(), String> {
+    let (sha256, size) = digest(image_path)?;
+
+    let mut metadata = Metadata {
+        format: FORMAT,
+        name: name.to_string(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .str_result()?
+            .as_secs(),
+        laboratory: env!("CARGO_PKG_VERSION").to_string(),
+        image: Member {
+            path: IMAGE.to_string(),
+            sha256,
+            size,
+        },
+        layers: Vec::new(),
+    };
+
+    for (i, layer) in layers.iter().enumerate() {
+        let (sha256, size) = digest(layer)?;
+
+        metadata.layers.push(Member {
+            path: format!("layers/{}.tar", i),
+            sha256,
+            size,
+        });
+    }
+
+    let signature = signature::signature_path(image_path);
+    let metadata_source = toml::to_string(&metadata).str_result()?;
+
+    let temporary = path.to_string() + ".part";
+
+    let result = manifest::write_archive(&temporary, None, |builder| {
+        for (name, source) in [(METADATA, metadata_source.as_str()), (CONFIG, config)] {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(source.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(metadata.created);
+
+            builder
+                .append_data(&mut header, name, source.as_bytes())
+                .str_result()?;
+        }
+
+        builder
+            .append_path_with_name(image_path, IMAGE)
+            .str_result()?;
+
+        for (layer, member) in layers.iter().zip(&metadata.layers) {
+            builder
+                .append_path_with_name(layer, &member.path)
+                .str_result()?;
+
+            let signature = signature::signature_path(layer);
+
+            if Path::new(&signature).exists() {
+                builder
+                    .append_path_with_name(&signature, signature::signature_path(&member.path))
+                    .str_result()?;
+            }
+        }
+
+        if Path::new(&signature).exists() {
+            builder
+                .append_path_with_name(&signature, SIGNATURE)
+                .str_result()?;
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        remove_file(&temporary).ok();
+
+        return Err(e);
+    }
+
+    rename(&temporary, path).str_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string, write as write_file};
+
+    use tar::Builder;
+
+    use super::*;
+    use crate::testing::Scratch;
+
+    const LAB: &str = "name = \"lab\"
+
+[[apps]]
+name = \"tool\"
+command = \"/bin/tool\"
+";
+
+    fn bundled(scratch: &Scratch) -> String {
+        let image = scratch.write("source/image.tar", "image");
+        let layer = scratch.write("source/base.tar", "layer");
+        scratch.write("source/base.tar.sig", "signed");
+        let path = scratch.path("lab.bundle");
+
+        write(&path, "lab", LAB, &image, &[layer]).unwrap();
+
+        path
+    }
+
+    // the members are copied over as they are, with the checked metadata in front
+    fn rebuilt(scratch: &Scratch, path: &str, f: impl Fn(&str, &mut Header) -> Vec<u8>) {
+        let mut members = Vec::new();
+        let mut archive = Archive::new(File::open(path).unwrap());
+
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = manifest::normalize(&entry.path().unwrap());
+
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+
+            members.push((name, entry.header().clone(), data));
+        }
+
+        let mut builder = Builder::new(File::create(scratch.path("tampered.bundle")).unwrap());
+
+        for (name, mut header, data) in members {
+            let data = match f(&name, &mut header) {
+                replaced if replaced.is_empty() => data,
+                replaced => replaced,
+            };
+
+            match header.entry_type() {
+                EntryType::Symlink => builder.append_link(&mut header, &name, "/etc/passwd"),
+                _ => {
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, &name, data.as_slice())
+                }
+            }
+            .unwrap();
+        }
+
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let scratch = Scratch::new();
+        let bundle = Bundle::open(&bundled(&scratch)).unwrap();
+
+        assert_eq!(bundle.metadata.name, "lab");
+        assert_eq!(bundle.config().unwrap().name, "lab");
+
+        let image = scratch.path("unpacked/image.tar");
+        create_dir_all(scratch.path("unpacked")).unwrap();
+
+        let layers = bundle.unpack(&image).unwrap();
+
+        assert_eq!(read_to_string(&image).unwrap(), "image");
+        assert_eq!(layers.len(), 1);
+        assert_eq!(read_to_string(&layers[0]).unwrap(), "layer");
+        assert_eq!(
+            read_to_string(signature::signature_path(&layers[0])).unwrap(),
+            "signed"
+        );
+    }
+
+    #[test]
+    fn tampered_members_are_refused_and_removed() {
+        let scratch = Scratch::new();
+        let path = bundled(&scratch);
+
+        rebuilt(&scratch, &path, |name, _| match name.eq(IMAGE) {
+            true => b"imagf".to_vec(),
+            false => Vec::new(),
+        });
+
+        let bundle = Bundle::open(&scratch.path("tampered.bundle")).unwrap();
+        let image = scratch.path("image.tar");
+
+        assert!(bundle.unpack(&image).is_err());
+        assert!(!Path::new(&image).exists());
+        assert!(!Path::new(&(image + ".layer0")).exists());
+    }
+
+    #[test]
+    fn links_are_not_followed() {
+        let scratch = Scratch::new();
+        let path = bundled(&scratch);
+
+        rebuilt(&scratch, &path, |name, header| {
+            if name.eq(IMAGE) {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+            }
+
+            Vec::new()
+        });
+
+        let bundle = Bundle::open(&scratch.path("tampered.bundle")).unwrap();
+        let image = scratch.path("image.tar");
+
+        assert!(bundle.unpack(&image).is_err());
+        assert!(Path::new(&image).symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn existing_files_are_never_overwritten() {
+        let scratch = Scratch::new();
+        let bundle = Bundle::open(&bundled(&scratch)).unwrap();
+        let image = scratch.path("image.tar");
+
+        write_file(&image, "mine").unwrap();
+
+        assert!(bundle.unpack(&image).is_err());
+        assert_eq!(read_to_string(&image).unwrap(), "mine");
+    }
+}
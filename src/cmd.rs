@@ -4,19 +4,52 @@ use colored::*;
 
 pub enum RunOptions {
     Exit,
-    Import(String, Option<String>),
-    List,
+    Import(Option<String>, Option<String>, Vec<String>, bool, Option<Source>),
+    Validate(String),
+    Check(String),
+    List(Option<String>),
     ListApps(String),
-    Run(String, Option<String>, Option<String>, Option<Vec<String>>),
+    Run(String, Option<String>, Option<String>, Option<Vec<String>>, Vec<(String, String)>, Option<String>),
+    RunGroup(String, String, Vec<(String, String)>, Option<String>),
     Change(String, Option<String>),
     Update(String, Option<String>),
-    Expand(String, Option<String>),
+    Expand(String, Option<String>, bool, Option<String>),
+    ExportConfig(String, Option<String>),
+    Export(String, String),
     Discard(String),
-    Repack(String),
-    Restore(String),
+    Repack(String, bool, Option<String>),
+    Restore(String, bool, Option<String>),
+    Snapshot(String, Option<String>, Option<String>),
+    Snapshots(String),
+    Rollback(String, String, Option<String>),
+    RemoveSnapshot(String, String),
+    Verify(String),
+    Sign(String, Option<String>),
+    GenKey(String),
+    Gc,
     Remove(String),
     Mount(String, Option<String>),
-    Unmount(String)
+    Unmount(String),
+    AppAdd(String, String, AppChanges),
+    AppEdit(String, String, AppChanges),
+    AppRemove(String, String),
+    EnvSet(String, String, String, String),
+    EnvUnset(String, String, String)
+}
+
+// where an import comes from when it is not a lab image
+pub enum Source {
+    Dir(String),
+    Oci(String),
+    Bundle(String)
+}
+
+#[derive(Default)]
+pub struct AppChanges {
+    pub name: Option<String>,
+    pub command: Option<String>,
+    pub work_dir: Option<String>,
+    pub args: Option<Vec<String>>
 }
 
 #[inline(always)]
@@ -31,17 +64,67 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
 
             return Ok(RunOptions::Exit);
         } else if arg.eq("-I") || arg.eq("--import") {
-            output = RunOptions::Import(
+            // a bundle carries its own config
+            let config = match args.next() {
+                Some(t) if t.eq("--bundle") => None,
+                Some(t) => Some(t),
+                None => { usage_and_return!(); }
+            };
+            let source = match config {
+                Some(_) => None,
+                None => match args.next() {
+                    Some(t) => Some(Source::Bundle(t)),
+                    None => { usage_and_return!(); }
+                }
+            };
+
+            output = RunOptions::Import(config, None, Vec::new(), false, source);
+
+            continue;
+        } else if arg.eq("--bundle") {
+            if let RunOptions::Import(_, _, _, _, source) = &mut output {
+                *source = match args.next() {
+                    Some(t) => Some(Source::Bundle(t)),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--layer") {
+            if let RunOptions::Import(_, _, layers, _, _) = &mut output {
                 match args.next() {
-                    Some(t) => t,
+                    Some(t) => layers.push(t),
                     None => { usage_and_return!(); }
-                },
-                None,
-            );
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--from-dir") {
+            if let RunOptions::Import(_, _, _, _, source) = &mut output {
+                *source = match args.next() {
+                    Some(t) => Some(Source::Dir(t)),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--oci") {
+            if let RunOptions::Import(_, _, _, _, source) = &mut output {
+                *source = match args.next() {
+                    Some(t) => Some(Source::Oci(t)),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--store") {
+            if let RunOptions::Import(_, _, _, store, _) = &mut output {
+                *store = true;
+            } else { usage_and_return!(); }
 
             continue;
         } else if arg.eq("-i") || arg.eq("--image") {
-            if let RunOptions::Import(_, image) = &mut output {
+            if let RunOptions::Import(_, image, _, _, _) = &mut output {
                 *image = match args.next() {
                     Some(t) => Some(t),
                     None => { usage_and_return!(); }
@@ -53,6 +136,20 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
                 };
             } else { usage_and_return!(); }
 
+            continue;
+        } else if arg.eq("-V") || arg.eq("--validate") {
+            output = RunOptions::Validate(match args.next() {
+                Some(t) => t,
+                None => { usage_and_return!(); }
+            });
+
+            continue;
+        } else if arg.eq("-C") || arg.eq("--check") {
+            output = RunOptions::Check(match args.next() {
+                Some(t) => t,
+                None => { usage_and_return!(); }
+            });
+
             continue;
         } else if arg.eq("-R") || arg.eq("--run") {
             output = RunOptions::Run(
@@ -62,21 +159,56 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
                 },
                 None,
                 None,
+                None,
+                Vec::new(),
+                None
+            );
+
+            continue;
+        } else if arg.eq("-G") || arg.eq("--run-group") {
+            output = RunOptions::RunGroup(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                Vec::new(),
                 None
             );
 
             continue;
         } else if arg.eq("-a") || arg.eq("--app") {
-            if let RunOptions::Run(_, app, _, _) = &mut output {
+            if let RunOptions::Run(_, app, _, _, _, _) = &mut output {
                 *app = match args.next() {
                     Some(t) => Some(t),
                     None => { usage_and_return!(); }
                 };
             } else { usage_and_return!(); }
 
+            continue;
+        } else if arg.eq("-P") || arg.eq("--param") {
+            if let RunOptions::Run(_, _, _, _, params, _) | RunOptions::RunGroup(_, _, params, _) = &mut output {
+                match args.next().as_ref().and_then(|t| t.split_once('=')) {
+                    Some((key, value)) => params.push((key.to_string(), value.to_string())),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--profile") {
+            if let RunOptions::Run(_, _, _, _, _, profile) | RunOptions::RunGroup(_, _, _, profile) = &mut output {
+                *profile = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
             continue;
         } else if arg.eq("-d") || arg.eq("--drive-letter") {
-            if let RunOptions::Run(_, _, drive_letter, _) = &mut output {
+            if let RunOptions::Run(_, _, drive_letter, _, _, _) = &mut output {
                 *drive_letter = match args.next() {
                     Some(t) => Some(t),
                     None => { usage_and_return!(); }
@@ -90,11 +222,80 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
 
             continue;
         } else if arg.eq("--") {
-            if let RunOptions::Run(_, _, _, arg_vector) = &mut output {
+            if let RunOptions::Run(_, _, _, arg_vector, _, _) = &mut output {
                 *arg_vector = Some(args.collect());
 
                 return Ok(output);
+            } else if let RunOptions::AppAdd(_, _, changes) | RunOptions::AppEdit(_, _, changes) = &mut output {
+                changes.args = Some(args.collect());
+
+                return Ok(output);
+            } else { usage_and_return!(); }
+        } else if arg.eq("app") {
+            let action = match args.next() {
+                Some(t) => t,
+                None => { usage_and_return!(); }
+            };
+            let (lab, app) = match (args.next(), args.next()) {
+                (Some(lab), Some(app)) => (lab, app),
+                _ => { usage_and_return!(); }
+            };
+
+            output = match action.as_str() {
+                "add" => RunOptions::AppAdd(lab, app, AppChanges::default()),
+                "edit" => RunOptions::AppEdit(lab, app, AppChanges::default()),
+                "remove" => RunOptions::AppRemove(lab, app),
+                _ => { usage_and_return!(); }
+            };
+
+            continue;
+        } else if arg.eq("env") {
+            let action = match args.next() {
+                Some(t) => t,
+                None => { usage_and_return!(); }
+            };
+            let (lab, app, env) = match (args.next(), args.next(), args.next()) {
+                (Some(lab), Some(app), Some(env)) => (lab, app, env),
+                _ => { usage_and_return!(); }
+            };
+
+            output = match action.as_str() {
+                "set" => match env.split_once('=') {
+                    Some((key, value)) => RunOptions::EnvSet(lab, app, key.to_string(), value.to_string()),
+                    None => { usage_and_return!(); }
+                },
+                "unset" => RunOptions::EnvUnset(lab, app, env),
+                _ => { usage_and_return!(); }
+            };
+
+            continue;
+        } else if arg.eq("--command") {
+            if let RunOptions::AppAdd(_, _, changes) | RunOptions::AppEdit(_, _, changes) = &mut output {
+                changes.command = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("-w") || arg.eq("--work-dir") {
+            if let RunOptions::AppAdd(_, _, changes) | RunOptions::AppEdit(_, _, changes) = &mut output {
+                changes.work_dir = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
             } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--rename") {
+            if let RunOptions::AppEdit(_, _, changes) = &mut output {
+                changes.name = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
         } else if arg.eq("-c") || arg.eq("--change") {
             output = RunOptions::Change(
                 match args.next() {
@@ -111,12 +312,37 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
                     Some(t) => t,
                     None => { usage_and_return!(); }
                 },
+                None,
+                true,
                 None
             );
 
+            continue;
+        } else if arg.eq("-X") || arg.eq("--export-config") {
+            output = RunOptions::ExportConfig(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                None
+            );
+
+            continue;
+        } else if arg.eq("--export") {
+            output = RunOptions::Export(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                }
+            );
+
             continue;
         } else if arg.eq("-p") || arg.eq("--path") {
-            if let RunOptions::Expand(_, path) = &mut output {
+            if let RunOptions::Expand(_, path, _, _) = &mut output {
                 *path = match args.next() {
                     Some(t) => Some(t),
                     None => { usage_and_return!(); }
@@ -126,6 +352,11 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
                     Some(t) => Some(t),
                     None => { usage_and_return!(); }
                 };
+            } else if let RunOptions::ExportConfig(_, path) = &mut output {
+                *path = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
             } else { usage_and_return!(); }
 
             continue;
@@ -147,10 +378,14 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
 
             continue;
         } else if arg.eq("-r") || arg.eq("--repack") {
-            output = RunOptions::Repack(match args.next() {
-                Some(t) => t,
-                None => { usage_and_return!(); }
-            });
+            output = RunOptions::Repack(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                false,
+                None
+            );
 
             continue;
         } else if arg.eq("-rm") || arg.eq("--remove") {
@@ -168,7 +403,118 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
 
             continue;
         } else if arg.eq("-rs") || arg.eq("--restore") {
-            output = RunOptions::Restore(match args.next() {
+            output = RunOptions::Restore(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                true,
+                None
+            );
+
+            continue;
+        } else if arg.eq("--no-verify") {
+            if let RunOptions::Expand(_, _, verify, _) | RunOptions::Restore(_, verify, _) = &mut output {
+                *verify = false;
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--encrypt") {
+            if let RunOptions::Repack(_, encrypt, _) = &mut output {
+                *encrypt = true;
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--key-file") {
+            if let RunOptions::Repack(_, _, key_file)
+                | RunOptions::Expand(_, _, _, key_file)
+                | RunOptions::Restore(_, _, key_file)
+                | RunOptions::Snapshot(_, _, key_file)
+                | RunOptions::Rollback(_, _, key_file) = &mut output {
+                *key_file = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--snapshot") {
+            output = RunOptions::Snapshot(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                None,
+                None
+            );
+
+            continue;
+        } else if arg.eq("--snapshots") {
+            output = RunOptions::Snapshots(match args.next() {
+                Some(t) => t,
+                None => { usage_and_return!(); }
+            });
+
+            continue;
+        } else if arg.eq("--rollback") {
+            output = RunOptions::Rollback(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                None
+            );
+
+            continue;
+        } else if arg.eq("--remove-snapshot") {
+            output = RunOptions::RemoveSnapshot(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                }
+            );
+
+            continue;
+        } else if arg.eq("--sign") {
+            output = RunOptions::Sign(
+                match args.next() {
+                    Some(t) => t,
+                    None => { usage_and_return!(); }
+                },
+                None
+            );
+
+            continue;
+        } else if arg.eq("-k") || arg.eq("--key") {
+            if let RunOptions::Sign(_, key) = &mut output {
+                *key = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if arg.eq("--gen-key") {
+            output = RunOptions::GenKey(match args.next() {
+                Some(t) => t,
+                None => { usage_and_return!(); }
+            });
+
+            continue;
+        } else if arg.eq("--gc") {
+            output = RunOptions::Gc;
+
+            continue;
+        } else if arg.eq("--verify") {
+            output = RunOptions::Verify(match args.next() {
                 Some(t) => t,
                 None => { usage_and_return!(); }
             });
@@ -192,7 +538,23 @@ pub fn parse_args(mut args: Args) -> Result<RunOptions, String> {
 
             continue;
         } else if arg.eq("-l") || arg.eq("--list") {
-            return Ok(RunOptions::List);
+            output = RunOptions::List(None);
+
+            continue;
+        } else if arg.eq("-t") || arg.eq("--tag") {
+            if let RunOptions::List(tag) = &mut output {
+                *tag = match args.next() {
+                    Some(t) => Some(t),
+                    None => { usage_and_return!(); }
+                };
+            } else { usage_and_return!(); }
+
+            continue;
+        } else if let (RunOptions::Snapshot(_, snapshot @ None, _), false) = (&mut output, arg.starts_with('-')) {
+            // the snapshot name is the only optional positional argument
+            *snapshot = Some(arg);
+
+            continue;
         } else {
             println!("Unknown option: {}", arg.red().bold());
 
@@ -237,16 +599,40 @@ pub fn print_usage() {
     println!("     Import laboratory");
     print!("  {}, {} {}", "-i".cyan().bold(), "--image".cyan().bold(), "<IMAGE>".cyan());
     println!("               Choose image");
+    print!("  {} {}", "--layer".cyan().bold(), "<IMAGE>".cyan());
+    println!("                   Add shared base layer below image");
+    print!("  {} {}", "--from-dir".cyan().bold(), "<DIR>".cyan());
+    println!("                  Import existing folder, needs image or store");
+    print!("  {} {}", "--oci".cyan().bold(), "<TARBALL>".cyan());
+    println!("                   Import docker save or oci layout tarball");
+    print!("  {} {}", "--bundle".cyan().bold(), "<FILE>".cyan());
+    println!("                   Import laboratory bundle, config optional");
+    print!("  {}", "--store".cyan().bold());
+    println!("                           Import image into the local store");
+    print!("  {}, {} {}", "-V".cyan().bold(), "--validate".cyan().bold(), "<CONFIG>".cyan());
+    println!("           Validate laboratory configuration");
+    print!("  {}, {} {}", "-C".cyan().bold(), "--check".cyan().bold(), "<LAB>".cyan());
+    println!("                 Check laboratory requirements");
     print!("  {}, {} {} {}", "-R".cyan().bold(), "--run".cyan().bold(), "<LAB>".cyan(), "[APP]".cyan());
     println!("             Run app from laboratory");
+    print!("  {}, {} {} {}", "-G".cyan().bold(), "--run-group".cyan().bold(), "<LAB>".cyan(), "<GROUP>".cyan());
+    println!("     Run app group from laboratory");
     print!("  {}, {} {}", "-a".cyan().bold(), "--app".cyan().bold(), "<APP>".cyan());
     println!("                   Choose app");
+    print!("  {}, {} {}", "-P".cyan().bold(), "--param".cyan().bold(), "<KEY=VALUE>".cyan());
+    println!("           Set app parameter");
+    print!("  {} {}", "--profile".cyan().bold(), "<NAME>".cyan());
+    println!("                  Choose profile");
     print!("  {}, {} {}", "-d".cyan().bold(), "--drive-letter".cyan().bold(), "<LETTER>".cyan());
     println!("       Choose drive letter");
     print!("  {}, {} {} {}", "-c".cyan().bold(), "--change".cyan().bold(), "<LAB>".cyan(), "[IMAGE]".cyan());
     println!("        Change laboratory image");
     print!("  {}, {} {} {}", "-U".cyan().bold(), "--update".cyan().bold(), "<LAB>".cyan(), "[PATH]".cyan());
     println!("         Update laboratory configuration");
+    print!("  {}, {} {} {}", "-X".cyan().bold(), "--export-config".cyan().bold(), "<LAB>".cyan(), "[PATH]".cyan());
+    println!("  Export laboratory configuration");
+    print!("  {} {} {}", "--export".cyan().bold(), "<LAB>".cyan(), "<FILE>".cyan());
+    println!("             Export laboratory as a bundle");
     print!("  {}, {} {} {}", "-e".cyan().bold(), "--expand".cyan().bold(), "<LAB>".cyan(), "[PATH]".cyan());
     println!("         Expand laboratory");
     print!("  {}, {} {}", "-p".cyan().bold(), "--path".cyan().bold(), "<PATH>".cyan());
@@ -259,15 +645,63 @@ pub fn print_usage() {
     println!("               Discard and remove expanded folder");
     print!("  {}, {} {}", "-r".cyan().bold(), "--repack".cyan().bold(), "<LAB>".cyan());
     println!("                Repack laboratory");
+    print!("  {}", "--encrypt".cyan().bold());
+    println!("                         Encrypt image with a passphrase");
+    print!("  {} {}", "--key-file".cyan().bold(), "<PATH>".cyan());
+    println!("                 Encrypt or decrypt image with a key file");
     print!("  {}, {} {}", "-rs".cyan().bold(), "--restore".cyan().bold(), "<LAB>".cyan());
     println!("              Restore laboratory");
+    print!("  {} {} {}", "--snapshot".cyan().bold(), "<LAB>".cyan(), "[NAME]".cyan());
+    println!("           Snapshot expanded laboratory");
+    print!("  {} {}", "--snapshots".cyan().bold(), "<LAB>".cyan());
+    println!("                 List snapshots");
+    print!("  {} {} {}", "--rollback".cyan().bold(), "<LAB>".cyan(), "<NAME>".cyan());
+    println!("           Roll expanded laboratory back to snapshot");
+    print!("  {} {} {}", "--remove-snapshot".cyan().bold(), "<LAB>".cyan(), "<NAME>".cyan());
+    println!("    Remove snapshot");
+    print!("  {} {}", "--verify".cyan().bold(), "<LAB>".cyan());
+    println!("                    Verify laboratory image checksum");
+    print!("  {}", "--no-verify".cyan().bold());
+    println!("                       Skip image verification on expand and restore");
+    print!("  {} {}", "--sign".cyan().bold(), "<LAB>".cyan());
+    println!("                      Sign laboratory image and its layers");
+    print!("  {}, {} {}", "-k".cyan().bold(), "--key".cyan().bold(), "<KEY>".cyan());
+    println!("                   Choose signing key");
+    print!("  {} {}", "--gen-key".cyan().bold(), "<KEY>".cyan());
+    println!("                   Generate signing key pair");
+    print!("  {}", "--gc".cyan().bold());
+    println!("                              Remove unreferenced blobs from the store");
     print!("  {}, {} {}", "-rm".cyan().bold(), "--remove".cyan().bold(), "<LAB>".cyan());
     println!("               Remove laboratory");
     print!("  {}, {}", "-l".cyan().bold(), "--list".cyan().bold());
     println!("                        List laboratories");
+    print!("  {}, {} {}", "-t".cyan().bold(), "--tag".cyan().bold(), "<TAG>".cyan());
+    println!("                   Filter laboratories by tag");
     print!("  {}, {} {}", "-L".cyan().bold(), "--list-apps".cyan().bold(), "<LAB>".cyan());
     println!("             List apps");
 
+    println!("\n{}", "Commands:".green().bold());
+    print!("  {} {} {}", "app add".cyan().bold(), "<LAB>".cyan(), "<APP>".cyan());
+    println!("               Add app");
+    print!("  {} {} {}", "app edit".cyan().bold(), "<LAB>".cyan(), "<APP>".cyan());
+    println!("              Edit app");
+    print!("  {} {} {}", "app remove".cyan().bold(), "<LAB>".cyan(), "<APP>".cyan());
+    println!("            Remove app");
+    print!("  {} {} {} {}", "env set".cyan().bold(), "<LAB>".cyan(), "<APP>".cyan(), "<KEY=VALUE>".cyan());
+    println!("   Set app env");
+    print!("  {} {} {} {}", "env unset".cyan().bold(), "<LAB>".cyan(), "<APP>".cyan(), "<KEY>".cyan());
+    println!("       Unset app env");
+
+    println!("\n{}", "App options:".green().bold());
+    print!("  {} {}", "--command".cyan().bold(), "<COMMAND>".cyan());
+    println!("               Set command");
+    print!("  {}, {} {}", "-w".cyan().bold(), "--work-dir".cyan().bold(), "<DIR>".cyan());
+    println!("              Set working directory");
+    print!("  {} {}", "--rename".cyan().bold(), "<NAME>".cyan());
+    println!("                   Rename app");
+    print!("  {} {}", "--".cyan().bold(), "<ARGS>...".cyan());
+    println!("                      Set arguments");
+
     println!("");
 }
 
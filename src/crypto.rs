@@ -0,0 +1,336 @@
+use std::{
+    fs::{read, File},
+    io::{self, Read, Write},
+    mem,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+    KeyInit, XChaCha20Poly1305,
+};
+use sha2::{Digest, Sha256};
+
+use crate::cmd::StrResult;
+
+// layout: magic, key kind, salt, stream nonce, then sealed chunks of `CHUNK` bytes each.
+// the final chunk is sealed as last, so a truncated image fails to decrypt.
+
+const MAGIC: &[u8; 8] = b"LABENC01";
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+const SALT: usize = 16;
+const NONCE: usize = 19;
+
+pub enum Secret {
+    Passphrase(String),
+    KeyFile(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyKind {
+    Passphrase,
+    KeyFile,
+}
+
+impl Secret {
+    #[inline(always)]
+    fn kind(&self) -> KeyKind {
+        match self {
+            Secret::Passphrase(_) => KeyKind::Passphrase,
+            Secret::KeyFile(_) => KeyKind::KeyFile,
+        }
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<[u8; 32], String> {
+        let mut key = [0u8; 32];
+
+        match self {
+            Secret::Passphrase(passphrase) => {
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .str_result()?;
+            }
+            Secret::KeyFile(path) => {
+                let mut hasher = Sha256::new();
+                hasher.update(salt);
+                hasher.update(read(path).map_err(|e| format!("{}: {}", path, e))?);
+
+                key.copy_from_slice(&hasher.finalize());
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+// None for a plain tar image
+pub fn detect(path: &str) -> Result<Option<KeyKind>, String> {
+    let mut file = File::open(path).str_result()?;
+
+    let mut header = [0u8; 9];
+
+    match file.read_exact(&mut header) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+
+    if !header[..8].eq(MAGIC) {
+        return Ok(None);
+    }
+
+    match header[8] {
+        0 => Ok(Some(KeyKind::Passphrase)),
+        1 => Ok(Some(KeyKind::KeyFile)),
+        _ => Err("Unknown image encryption!".to_string()),
+    }
+}
+
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, secret: &Secret) -> Result<Self, String> {
+        let mut salt = [0u8; SALT];
+        let mut nonce = [0u8; NONCE];
+        getrandom::getrandom(&mut salt).str_result()?;
+        getrandom::getrandom(&mut nonce).str_result()?;
+
+        let kind = match secret.kind() {
+            KeyKind::Passphrase => 0,
+            KeyKind::KeyFile => 1,
+        };
+
+        inner.write_all(MAGIC).str_result()?;
+        inner.write_all(&[kind]).str_result()?;
+        inner.write_all(&salt).str_result()?;
+        inner.write_all(&nonce).str_result()?;
+
+        let cipher = XChaCha20Poly1305::new(&secret.derive(&salt)?.into());
+
+        Ok(Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(cipher, nonce.as_ref().into())),
+            buffer: Vec::with_capacity(CHUNK),
+        })
+    }
+
+    // seals the last chunk; dropping the writer without finishing leaves an unreadable image
+    pub fn finish(mut self) -> Result<W, String> {
+        let encryptor = match self.encryptor.take() {
+            Some(encryptor) => encryptor,
+            None => return Err("Image already finished!".to_string()),
+        };
+
+        let sealed = encryptor
+            .encrypt_last(self.buffer.as_slice())
+            .map_err(|_| "Failed to encrypt image!".to_string())?;
+
+        self.inner.write_all(&sealed).str_result()?;
+        self.inner.flush().str_result()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // a full chunk is only sealed once more data arrives, the final one is left to `finish`
+        if self.buffer.len() == CHUNK {
+            let encryptor = self
+                .encryptor
+                .as_mut()
+                .ok_or_else(|| io::Error::other("Image already finished!"))?;
+
+            let sealed = encryptor
+                .encrypt_next(self.buffer.as_slice())
+                .map_err(|_| io::Error::other("Failed to encrypt image!"))?;
+
+            self.inner.write_all(&sealed)?;
+            self.buffer.clear();
+        }
+
+        let taken = data.len().min(CHUNK - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..taken]);
+
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    pending: Vec<u8>,
+    plain: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, secret: &Secret) -> Result<Self, String> {
+        let mut header = [0u8; 9 + SALT + NONCE];
+        inner.read_exact(&mut header).str_result()?;
+
+        if !header[..8].eq(MAGIC) {
+            return Err("Image is not encrypted!".to_string());
+        }
+
+        let (salt, nonce) = header[9..].split_at(SALT);
+
+        let cipher = XChaCha20Poly1305::new(&secret.derive(salt)?.into());
+
+        let mut reader = Self {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(cipher, nonce.into())),
+            pending: Vec::new(),
+            plain: Vec::new(),
+            position: 0,
+        };
+
+        reader.pending = reader.read_sealed().str_result()?;
+
+        // the first chunk is opened right away so a wrong key fails before anything is unpacked
+        reader.refill().str_result()?;
+
+        Ok(reader)
+    }
+
+    fn read_sealed(&mut self) -> io::Result<Vec<u8>> {
+        let mut sealed = Vec::with_capacity(CHUNK + TAG);
+
+        (&mut self.inner)
+            .take((CHUNK + TAG) as u64)
+            .read_to_end(&mut sealed)?;
+
+        Ok(sealed)
+    }
+
+    // one sealed chunk is kept ahead to know which one is the last
+    fn refill(&mut self) -> io::Result<()> {
+        let decryptor = match self.decryptor.take() {
+            Some(decryptor) => decryptor,
+            None => return Ok(()),
+        };
+
+        let next = self.read_sealed()?;
+        let current = mem::replace(&mut self.pending, next);
+
+        let wrong = || io::Error::new(io::ErrorKind::InvalidData, "Wrong key or corrupted image!");
+
+        self.plain = match self.pending.is_empty() {
+            true => decryptor
+                .decrypt_last(current.as_slice())
+                .map_err(|_| wrong())?,
+            false => {
+                let mut decryptor = decryptor;
+                let plain = decryptor
+                    .decrypt_next(current.as_slice())
+                    .map_err(|_| wrong())?;
+
+                self.decryptor = Some(decryptor);
+
+                plain
+            }
+        };
+        self.position = 0;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+
+            self.refill()?;
+        }
+
+        let read = buffer.len().min(self.plain.len() - self.position);
+        buffer[..read].copy_from_slice(&self.plain[self.position..self.position + read]);
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Scratch;
+
+    // more than two chunks, so the last one is sealed on its own
+    fn plain() -> Vec<u8> {
+        (0..CHUNK * 2 + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn seal(plain: &[u8], secret: &Secret) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), secret).unwrap();
+        writer.write_all(plain).unwrap();
+
+        writer.finish().unwrap()
+    }
+
+    fn open(sealed: &[u8], secret: &Secret) -> Result<Vec<u8>, String> {
+        let mut plain = Vec::new();
+
+        DecryptReader::new(sealed, secret)?
+            .read_to_end(&mut plain)
+            .str_result()?;
+
+        Ok(plain)
+    }
+
+    #[test]
+    fn round_trip_with_either_secret() {
+        let scratch = Scratch::new();
+        let key_file = Secret::KeyFile(scratch.write("key", "not much of a key"));
+        let passphrase = Secret::Passphrase("correct horse".to_string());
+
+        for secret in [key_file, passphrase] {
+            let sealed = seal(&plain(), &secret);
+
+            assert!(open(&sealed, &secret).unwrap() == plain());
+        }
+    }
+
+    #[test]
+    fn wrong_secret_fails_up_front() {
+        let sealed = seal(&plain(), &Secret::Passphrase("right".to_string()));
+
+        assert!(open(&sealed, &Secret::Passphrase("wrong".to_string())).is_err());
+    }
+
+    #[test]
+    fn truncated_image_fails() {
+        let secret = Secret::Passphrase("right".to_string());
+        let sealed = seal(&plain(), &secret);
+
+        // cut at a chunk boundary, so every chunk left still opens on its own
+        let cut = 9 + SALT + NONCE + CHUNK + TAG;
+
+        assert!(open(&sealed[..cut], &secret).is_err());
+        assert!(open(&sealed[..sealed.len() - 1], &secret).is_err());
+    }
+
+    #[test]
+    fn detect_tells_the_kind_apart() {
+        let scratch = Scratch::new();
+        let key_file = Secret::KeyFile(scratch.write("key", "key"));
+
+        let sealed = scratch.path("sealed");
+        std::fs::write(&sealed, seal(b"lab", &key_file)).unwrap();
+
+        assert!(detect(&sealed).unwrap() == Some(KeyKind::KeyFile));
+        assert!(detect(&scratch.write("plain", "lab")).unwrap().is_none());
+    }
+}
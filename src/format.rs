@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use toml::Table;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    // the extension wins, otherwise the content is sniffed
+    pub fn detect(path: &str, source: &str) -> Self {
+        let extension = Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => {
+                if source.trim_start().starts_with('{') {
+                    Format::Json
+                } else if toml::from_str::<toml::Table>(source).is_ok() {
+                    Format::Toml
+                } else {
+                    Format::Yaml
+                }
+            }
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, path: &str, source: &str) -> Result<T, String> {
+        match self {
+            Format::Toml => toml::from_str(source).map_err(|e| {
+                let position = e.span().map(|span| position(source, span.start));
+
+                located(path, position, e.message())
+            }),
+            Format::Json => serde_json::from_str(source).map_err(|e| {
+                let position = match e.line() {
+                    0 => None,
+                    line => Some((line, e.column())),
+                };
+
+                located(path, position, &e.to_string())
+            }),
+            Format::Yaml => serde_yaml::from_str(source).map_err(|e| {
+                let position = e.location().map(|l| (l.line(), l.column()));
+
+                located(path, position, &e.to_string())
+            }),
+        }
+    }
+
+    // generated json and yaml often spell out missing values as null, which toml has no room for
+    pub fn parse_table(&self, path: &str, source: &str) -> Result<Table, String> {
+        let value = match self {
+            Format::Toml => return self.parse(path, source),
+            Format::Json => from_json(self.parse(path, source)?),
+            Format::Yaml => {
+                from_yaml(self.parse(path, source)?).map_err(|e| located(path, None, &e))?
+            }
+        };
+
+        match value {
+            Some(toml::Value::Table(table)) => Ok(table),
+            _ => Err(located(path, None, "Config must be a table!")),
+        }
+    }
+}
+
+fn from_json(value: serde_json::Value) -> Option<toml::Value> {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => None,
+        Value::Bool(b) => Some(toml::Value::Boolean(b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(toml::Value::Integer(i)),
+            None => n.as_f64().map(toml::Value::Float),
+        },
+        Value::String(s) => Some(toml::Value::String(s)),
+        Value::Array(array) => Some(toml::Value::Array(
+            array.into_iter().filter_map(from_json).collect(),
+        )),
+        Value::Object(object) => Some(toml::Value::Table(
+            object
+                .into_iter()
+                .filter_map(|(k, v)| from_json(v).map(|v| (k, v)))
+                .collect(),
+        )),
+    }
+}
+
+fn from_yaml(value: serde_yaml::Value) -> Result<Option<toml::Value>, String> {
+    use serde_yaml::Value;
+
+    Ok(match value {
+        Value::Null => None,
+        Value::Bool(b) => Some(toml::Value::Boolean(b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(toml::Value::Integer(i)),
+            None => n.as_f64().map(toml::Value::Float),
+        },
+        Value::String(s) => Some(toml::Value::String(s)),
+        Value::Sequence(sequence) => {
+            let mut array = Vec::new();
+
+            for value in sequence {
+                array.extend(from_yaml(value)?);
+            }
+
+            Some(toml::Value::Array(array))
+        }
+        Value::Mapping(mapping) => {
+            let mut table = Table::new();
+
+            for (key, value) in mapping {
+                let key = match key {
+                    Value::String(key) => key,
+                    key => return Err(format!("Keys must be strings: {:?}", key)),
+                };
+
+                if let Some(value) = from_yaml(value)? {
+                    table.insert(key, value);
+                }
+            }
+
+            Some(toml::Value::Table(table))
+        }
+        Value::Tagged(tagged) => from_yaml(tagged.value)?,
+    })
+}
+
+pub fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+
+    let line = before.matches('\n').count() + 1;
+    let column = match before.rfind('\n') {
+        Some(index) => before[index + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+
+    (line, column)
+}
+
+pub fn located(path: &str, position: Option<(usize, usize)>, message: &str) -> String {
+    // json and yaml errors repeat the position at the end of the message
+    let message = match message.rsplit_once(" at line ") {
+        Some((message, _)) if position.is_some() => message,
+        _ => message,
+    };
+
+    match position {
+        Some((line, column)) => format!("{}:{}:{}: {}", path, line, column, message.trim_end()),
+        None => format!("{}: {}", path, message.trim_end()),
+    }
+}
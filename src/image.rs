@@ -1,109 +1,385 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env,
-    fs::{remove_dir_all, OpenOptions},
-    io::Read,
+    fs::{create_dir_all, metadata, remove_dir_all, remove_file, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
     process::{Child, Command},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tar::{Archive, Builder};
 
-use crate::cmd::StrResult;
+#[cfg(target_os = "linux")]
+use crate::sandbox;
+use crate::{
+    bundle,
+    cmd::{AppChanges, StrResult},
+    crypto::{self, DecryptReader, EncryptWriter, KeyKind, Secret},
+    layer,
+    manifest::{self, Report},
+    preflight,
+    signature::{self, Signing},
+    store::Store,
+    validate,
+};
+
+// hooks are shell lines, so they keep what the shell needs to find and start commands
+#[cfg(windows)]
+const HOOK_ENVS: [&str; 7] = [
+    "PATH",
+    "PATHEXT",
+    "SystemRoot",
+    "windir",
+    "ComSpec",
+    "TEMP",
+    "TMP",
+];
+#[cfg(not(windows))]
+const HOOK_ENVS: [&str; 4] = ["PATH", "HOME", "TMPDIR", "LANG"];
 
 #[derive(Serialize, Deserialize)]
 pub struct Lab {
     pub image_path: Option<String>,
+    pub image_sha256: Option<String>,
+    pub image_size: Option<u64>,
+    // stored labs keep no image of their own, only a tree in the store
+    pub tree: Option<String>,
+    // the trusted key the image was verified against, kept for labs without an image
+    pub signer: Option<String>,
     pub expanded_path: Option<String>,
     pub drive_letter: Option<String>,
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    pub layers: Vec<Layer>,
     pub config: LabConfig,
 }
 
+// a shared image below the lab's own, applied bottom first
+#[derive(Serialize, Deserialize)]
+pub struct Layer {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
 #[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub path: String,
+    pub created: u64,
+    pub sha256: String,
+    pub size: u64,
+    // a stored lab's snapshot is a tree in the store named by `sha256`, with no file of its own
+    #[serde(default)]
+    pub stored: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct LabConfig {
     pub name: String,
+    pub description: Option<String>,
+    pub version: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub homepage: Option<String>,
+    pub default_app: Option<String>,
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default)]
+    pub envs: Vec<Env>,
     pub apps: Vec<App>,
+    #[serde(default)]
+    pub pre_run: Vec<String>,
+    #[serde(default)]
+    pub post_run: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    pub requires: Option<Requires>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct App {
     pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
     pub command: String,
+    #[serde(default)]
     pub args: Vec<String>,
+    #[serde(default)]
     pub work_dir: String,
+    #[serde(default)]
+    pub envs: Vec<Env>,
+    #[serde(default)]
+    pub target: BTreeMap<String, Target>,
+    #[serde(default)]
+    pub params: Vec<Param>,
+    pub sandbox: Option<Sandbox>,
+    #[serde(default)]
+    pub pre_run: Vec<String>,
+    #[serde(default)]
+    pub post_run: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub command: Option<String>,
+    pub args: Option<Vec<String>>,
+    pub work_dir: Option<String>,
+    #[serde(default)]
+    pub envs: Vec<Env>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
     pub envs: Vec<Env>,
+    #[serde(default)]
+    pub apps: BTreeMap<String, ProfileApp>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileApp {
+    pub args: Option<Vec<String>>,
+    #[serde(default)]
+    pub envs: Vec<Env>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Env {
     pub key: String,
     pub value: String,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Param {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ParamKind,
+    #[serde(default)]
+    pub required: bool,
+    pub default: Option<String>,
+    #[serde(default)]
+    pub values: Vec<String>,
+    pub help: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamKind {
+    #[default]
+    String,
+    Int,
+    Path,
+    Enum,
+}
+
+impl ParamKind {
+    #[inline(always)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParamKind::String => "string",
+            ParamKind::Int => "int",
+            ParamKind::Path => "path",
+            ParamKind::Enum => "enum",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub name: String,
+    pub main: Option<String>,
+    pub members: Vec<Member>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Member {
+    pub app: String,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    pub ready: Option<Ready>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ready {
+    pub port: Option<u16>,
+    pub file: Option<String>,
+    pub delay_ms: Option<u64>,
+    #[serde(default = "Ready::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Ready {
+    #[inline(always)]
+    fn default_timeout_ms() -> u64 {
+        30_000
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Requires {
+    #[serde(default)]
+    pub executables: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<String>,
+    #[serde(default)]
+    pub envs: Vec<String>,
+    pub min_free_disk: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sandbox {
+    #[serde(default = "Sandbox::default_mount_point")]
+    pub mount_point: String,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub network: bool,
+}
+
+impl Sandbox {
+    #[inline(always)]
+    fn default_mount_point() -> String {
+        "/lab".to_string()
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            mount_point: Sandbox::default_mount_point(),
+            read_only: false,
+            network: false,
+        }
+    }
+}
+
+impl App {
+    #[inline(always)]
+    pub fn host_target(&self) -> Option<&Target> {
+        self.target.get(env::consts::OS)
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self.host_target() {
+            Some(target) => target.command.is_some() || !self.command.is_empty(),
+            None => !self.command.is_empty(),
+        }
+    }
+}
+
+// what a single launch was asked for: checked parameters and the selected profile
+pub struct Invocation {
+    pub params: HashMap<String, String>,
+    pub profile: Option<String>,
+}
+
+// an app with lab-level envs, variables and parameters applied
+struct Resolved<'a> {
+    app: &'a App,
+    command: String,
+    args: Vec<String>,
+    work_dir: String,
+    envs: Vec<Env>,
+}
+
 impl Lab {
     #[inline(always)]
     pub fn from_image(path: String) -> Self {
         Self {
             image_path: Some(path),
+            image_sha256: None,
+            image_size: None,
+            tree: None,
+            signer: None,
             expanded_path: None,
             drive_letter: None,
-            config: LabConfig {
-                name: "".to_string(),
-                apps: Vec::new(),
-            },
+            snapshots: Vec::new(),
+            layers: Vec::new(),
+            config: LabConfig::default(),
         }
     }
 
-    // #[inline(always)]
-    // pub fn from_expanded(path: String) -> Self {
-    //     Self {
-    //         image_path: None,
-    //         expanded_path: Some(path),
-    //         drive_letter: None,
-    //         config: None,
-    //     }
-    // }
+    #[inline(always)]
+    pub fn from_expanded(path: String) -> Self {
+        Self {
+            image_path: None,
+            image_sha256: None,
+            image_size: None,
+            tree: None,
+            signer: None,
+            expanded_path: Some(path),
+            drive_letter: None,
+            snapshots: Vec::new(),
+            layers: Vec::new(),
+            config: LabConfig::default(),
+        }
+    }
 
     pub fn read_config(&mut self, path: &str) -> Result<(), String> {
-        let mut file = OpenOptions::new().read(true).open(path).str_result()?;
-
-        let config: LabConfig = {
-            let mut toml = String::new();
-
-            file.read_to_string(&mut toml).str_result()?;
-
-            toml::from_str(&toml).str_result()?
-        };
-
-        self.config = config;
+        self.config = validate::check_file(path).map_err(|problems| problems.join("\n"))?;
 
         Ok(())
     }
 
-    // pub fn write_config(&self, path: &str) -> Result<(), String> {
-    //     if let Some(config) = &self.config {
-    //         let mut file = OpenOptions::new()
-    //             .write(true)
-    //             .create(true)
-    //             .truncate(true)
-    //             .open(path)
-    //             .str_result()?;
+    pub fn write_config(&self, path: &str) -> Result<(), String> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .str_result()?;
 
-    //         let toml = toml::to_string(config).unwrap();
+        let toml = self.config_string()?;
 
-    //         file.write_all(toml.as_bytes()).str_result()?;
-    //         file.sync_all().str_result()?;
+        file.write_all(toml.as_bytes()).str_result()?;
+        file.sync_all().str_result()?;
 
-    //         return Ok(());
-    //     }
+        Ok(())
+    }
 
-    //     Err("No config to write!".to_string())
-    // }
+    #[inline(always)]
+    pub fn config_string(&self) -> Result<String, String> {
+        toml::to_string(&self.config).str_result()
+    }
 
     pub fn discard(&mut self) -> Result<(), String> {
         if let Some(expanded_path) = &self.expanded_path {
+            // an adopted folder with nothing behind it is the only copy
+            if self.image_path.is_none() && self.tree.is_none() {
+                return Err("Lab has no image, discarding would lose it!".to_string());
+            }
+
             remove_dir_all(expanded_path).str_result()?;
 
+            if let Some(image_path) = &self.image_path {
+                manifest::remove(image_path)?;
+            }
+
             self.expanded_path = None;
 
             return Ok(());
@@ -112,26 +388,51 @@ impl Lab {
         Err("Lab not expanded!".to_string())
     }
 
-    pub fn repack(&mut self) -> Result<(), String> {
+    pub fn repack(&mut self, secret: Option<&Secret>) -> Result<Report, String> {
         if let Some(expanded_path) = &self.expanded_path {
+            if let Some(tree) = &self.tree {
+                if secret.is_some() {
+                    return Err("Stored labs can't be encrypted!".to_string());
+                }
+
+                let (tree, report) = Store::default().import_dir(expanded_path, Some(tree))?;
+
+                remove_dir_all(expanded_path).str_result()?;
+
+                self.expanded_path = None;
+                self.tree = Some(tree);
+
+                return Ok(report);
+            }
+
             if let Some(image_path) = &self.image_path {
-                let mut archive = Builder::new(
-                    OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .open(image_path)
-                        .str_result()?,
-                );
+                let manifest = manifest::load(image_path, self.image_sha256.as_deref())?;
 
-                archive.append_dir_all(".", expanded_path).str_result()?;
-                archive.into_inner().str_result()?.sync_all().str_result()?;
+                // an encrypted image can't be appended to or read without its old secret
+                let report = match manifest {
+                    _ if !self.layers.is_empty() => {
+                        let lower = self.lower(image_path, secret)?;
+
+                        layer::repack(image_path, expanded_path, &lower, secret)?
+                    }
+                    Some(manifest) if crypto::detect(image_path)?.is_none() => {
+                        manifest::repack(image_path, expanded_path, &manifest, secret)?
+                    }
+                    _ => {
+                        Self::pack(expanded_path, image_path, secret)?;
+
+                        manifest::full_report(expanded_path)?
+                    }
+                };
 
                 remove_dir_all(expanded_path).str_result()?;
+                manifest::remove(image_path)?;
 
                 self.expanded_path = None;
 
-                return Ok(());
+                self.record_image()?;
+
+                return Ok(report);
             }
 
             return Err("No image to repack!".to_string());
@@ -140,151 +441,1749 @@ impl Lab {
         Err("Lab not expanded!".to_string())
     }
 
-    pub fn restore(&self) -> Result<(), String> {
-        if let Some(expanded_path) = &self.expanded_path {
-            if let Some(image_path) = &self.image_path {
-                remove_dir_all(expanded_path).str_result()?;
+    pub fn add_layer(&mut self, path: String) -> Result<(), String> {
+        let (sha256, size) = digest(&path)?;
+
+        self.layers.push(Layer { path, sha256, size });
+
+        Ok(())
+    }
 
-                let mut archive = Archive::new(
-                    OpenOptions::new()
-                        .read(true)
-                        .open(image_path)
-                        .str_result()?,
-                );
+    // builds the first image, or tree, from the folder the lab was imported from
+    pub fn adopt(&mut self, store: bool) -> Result<(), String> {
+        let expanded_path = match &self.expanded_path {
+            Some(expanded_path) if Path::new(expanded_path).is_dir() => expanded_path.clone(),
+            Some(_) => return Err("Folder not found!".to_string()),
+            None => return Err("Lab not expanded!".to_string()),
+        };
+
+        let image_path = match (self.image_path.clone(), store) {
+            (Some(_), true) => return Err("Stored labs have no image!".to_string()),
+            (None, true) if !self.layers.is_empty() => {
+                return Err("Layered labs can't be stored!".to_string())
+            }
+            (None, true) => {
+                let (tree, _) = Store::default().import_dir(&expanded_path, None)?;
 
-                archive.unpack(expanded_path).str_result()?;
+                self.tree = Some(tree);
 
                 return Ok(());
             }
+            (None, false) if !self.layers.is_empty() => {
+                return Err("Layered labs need an image!".to_string())
+            }
+            (None, false) => return Ok(()),
+            (Some(image_path), false) => image_path,
+        };
 
-            return Err("No image to restore!".to_string());
+        if Path::new(&image_path).exists() {
+            return Err("Image already exists!".to_string());
         }
 
-        Err("Lab not expanded!".to_string())
+        match self.layers.is_empty() {
+            true => Self::pack(&expanded_path, &image_path, None)?,
+            false => {
+                let lower = self.lower(&image_path, None)?;
+
+                layer::repack(&image_path, &expanded_path, &lower, None)?;
+            }
+        }
+
+        self.record_image()?;
+        self.record_manifest()
     }
 
-    pub fn expand(&mut self, target_path: String) -> Result<(), String> {
+    // moves the image into the store, after which the lab no longer needs it
+    pub fn store(&mut self) -> Result<(), String> {
+        if !self.layers.is_empty() {
+            return Err("Layered labs can't be stored!".to_string());
+        }
+
         if let Some(image_path) = &self.image_path {
-            let mut archive = Archive::new(
-                OpenOptions::new()
-                    .read(true)
-                    .open(image_path)
-                    .str_result()?,
-            );
+            if crypto::detect(image_path)?.is_some() {
+                return Err("Encrypted images can't be stored!".to_string());
+            }
 
-            archive.unpack(&target_path).str_result()?;
+            let (tree, _) =
+                Store::default().import_archive(File::open(image_path).str_result()?)?;
 
-            self.expanded_path = Some(target_path);
+            self.tree = Some(tree);
+            self.image_path = None;
+            self.image_sha256 = None;
+            self.image_size = None;
 
             return Ok(());
         }
 
-        Err("No image to expand!".to_string())
+        Err("No image to store!".to_string())
     }
 
-    pub fn mount(&mut self, drive_letter: String) -> Result<(), String> {
-        match &self.drive_letter {
-            Some(d) => {
-                if !d.eq(&drive_letter) {
-                    self.unmount()?;
-                } else {
-                    return Ok(());
-                }
+    pub fn record_image(&mut self) -> Result<(), String> {
+        if let Some(image_path) = &self.image_path {
+            let (sha256, size) = digest(image_path)?;
+
+            self.image_sha256 = Some(sha256);
+            self.image_size = Some(size);
+
+            return Ok(());
+        }
+
+        Err("No image to record!".to_string())
+    }
+
+    // false when nothing was recorded, e.g. for labs imported before checksums existed
+    pub fn verify_image(&self) -> Result<bool, String> {
+        if let Some(tree) = &self.tree {
+            return Store::default().verify(tree).map(|_| true);
+        }
+
+        if let Some(image_path) = &self.image_path {
+            for layer in &self.layers {
+                check_digest(&layer.path, &layer.sha256, layer.size)
+                    .map_err(|e| format!("{}: {}", layer.path, e))?;
             }
-            None => {}
-        };
 
-        if let Some(expanded_path) = &self.expanded_path {
-            match Self::create_volume((drive_letter.clone() + ":").as_str(), &expanded_path) {
-                true => {
-                    self.drive_letter = Some(drive_letter);
+            return match (&self.image_sha256, self.image_size) {
+                (Some(sha256), Some(size)) => check_digest(image_path, sha256, size).map(|_| true),
+                _ => Ok(false),
+            };
+        }
 
-                    return Ok(());
-                }
-                false => return Err("Failed to create volume!".to_string()),
+        Err("No image to verify!".to_string())
+    }
+
+    // stored labs are packed from the store into a temporary image first
+    pub fn export(&self, path: &str) -> Result<(), String> {
+        // whatever is bundled gets a fresh checksum, so it has to be intact now
+        self.verify_image()?;
+
+        let config = self.config_string()?;
+        let layers: Vec<String> = self.layers.iter().map(|l| l.path.clone()).collect();
+
+        if let Some(tree) = &self.tree {
+            let temporary = path.to_string() + ".image";
+
+            let result = Store::default()
+                .write_image(tree, &temporary)
+                .and_then(|_| bundle::write(path, &self.config.name, &config, &temporary, &layers));
+
+            // the temporary image goes whether the bundle was written or not
+            remove_file(&temporary).ok();
+
+            return result;
+        }
+
+        if let Some(image_path) = &self.image_path {
+            return bundle::write(path, &self.config.name, &config, image_path, &layers);
+        }
+
+        Err("No image to export!".to_string())
+    }
+
+    pub fn sign_image(&self, key_path: &str) -> Result<String, String> {
+        if self.tree.is_some() {
+            return Err("Stored labs keep no image, sign it before storing!".to_string());
+        }
+
+        if let Some(image_path) = &self.image_path {
+            // shared layers are signed along with the image, they hold most of what runs
+            for layer in &self.layers {
+                let (sha256, _) = digest(&layer.path)?;
+
+                signature::sign(&layer.path, &sha256, key_path)?;
             }
+
+            let (sha256, _) = digest(image_path)?;
+
+            return signature::sign(image_path, &sha256, key_path);
         }
 
-        Err("Lab not expanded!".to_string())
+        Err("No image to sign!".to_string())
     }
 
-    pub fn unmount(&mut self) -> Result<(), String> {
-        if let Some(drive_letter) = &self.drive_letter {
-            match Self::delete_volume((drive_letter.clone() + ":").as_str()) {
-                true => {
-                    self.drive_letter = None;
+    pub fn verify_signature(&mut self, signing: &Signing) -> Result<(), String> {
+        if let Some(image_path) = &self.image_path {
+            for layer in &self.layers {
+                let (sha256, _) = digest(&layer.path)?;
 
-                    return Ok(());
-                }
-                false => return Err("Failed to delete volume!".to_string()),
+                signature::verify(&layer.path, &sha256, signing)
+                    .map_err(|e| format!("{}: {}", layer.path, e))?;
             }
+
+            let (sha256, _) = digest(image_path)?;
+
+            self.signer = signature::verify(image_path, &sha256, signing)?;
+
+            return Ok(());
         }
 
-        Err("Lab not mounted!".to_string())
+        // stored and adopted labs are held to the signer recorded when they were imported
+        match &self.signer {
+            _ if !signing.required => Ok(()),
+            Some(signer) if signing.trusts(signer) => Ok(()),
+            Some(signer) => Err(format!("Image is signed by an untrusted key: {}", signer)),
+            None => Err("Image is not signed!".to_string()),
+        }
     }
 
-    pub fn run(&self, app: &str, args: Option<Vec<String>>) -> Result<Child, String> {
-        if let Some(drive_letter) = &self.drive_letter {
-            for a in &self.config.apps {
-                if a.name.eq(app) {
-                    // run app and return handle
-                    let child = Command::new(drive_letter.clone() + ":" + &a.command)
-                        .env_clear()
-                        .current_dir(drive_letter.clone() + ":" + &a.work_dir)
-                        .envs(self.analyze_envs(&a)?)
-                        .args({
-                            let mut all_args = a.args.clone();
-
-                            match args {
-                                Some(mut a) => {
-                                    all_args.append(&mut a);
-                                }
-                                None => {}
-                            }
-
-                            all_args
-                        })
-                        .spawn()
-                        .str_result()?;
-
-                    return Ok(child);
+    pub fn restore(&self, secret: Option<&Secret>) -> Result<(), String> {
+        if let Some(expanded_path) = &self.expanded_path {
+            if let Some(tree) = &self.tree {
+                // loaded first so a missing tree leaves the expanded folder alone
+                let tree = Store::default().load(tree)?;
+
+                remove_dir_all(expanded_path).str_result()?;
+
+                return Store::default().checkout(&tree, Path::new(expanded_path));
+            }
+
+            if let Some(image_path) = &self.image_path {
+                match self.layers.is_empty() {
+                    true => Self::replace_expanded(image_path, expanded_path, secret)?,
+                    false => {
+                        // opened first so a missing secret leaves the expanded folder alone
+                        let readers = self.open_layers(secret, true)?;
+
+                        remove_dir_all(expanded_path).str_result()?;
+                        create_dir_all(expanded_path).str_result()?;
+
+                        for reader in readers {
+                            layer::unpack(reader, Path::new(expanded_path))?;
+                        }
+                    }
                 }
+
+                return self.record_manifest();
             }
 
-            return Err("App not found!".to_string());
+            return Err("No image to restore!".to_string());
         }
 
-        Err("Lab not mounted".to_string())
+        Err("Lab not expanded!".to_string())
     }
 
-    fn analyze_envs(&self, app: &App) -> Result<HashMap<String, String>, String> {
-        let mut analyzed: HashMap<String, String> = HashMap::new();
+    // snapshots sit next to the primary image and are encrypted like it
+    pub fn snapshot(&mut self, name: String, secret: Option<&Secret>) -> Result<(), String> {
+        if name.is_empty() || name.contains(['/', '\\', ':']) {
+            return Err("Invalid snapshot name!".to_string());
+        }
 
-        let drive_letter = self.drive_letter.as_ref().unwrap();
+        if self.snapshots.iter().any(|s| s.name.eq(&name)) {
+            return Err("Snapshot with similar name exists!".to_string());
+        }
 
-        for env in &app.envs {
-            let (key, mut value) = (env.key.clone(), env.value.clone());
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .str_result()?
+            .as_secs();
 
-            // key = key.replace("$mnt$", &drive_letter);
-            if value.eq("$sm$") {
-                value = env::var(&key).str_result()?;
-            } else {
-                value = value.replace("$mnt$", &drive_letter);
+        if let Some(expanded_path) = &self.expanded_path {
+            if let Some(tree) = &self.tree {
+                let (sha256, report) = Store::default().import_dir(expanded_path, Some(tree))?;
+
+                self.snapshots.push(Snapshot {
+                    name,
+                    path: String::new(),
+                    created,
+                    sha256,
+                    size: report.rewritten_bytes + report.reused_bytes,
+                    stored: true,
+                });
+
+                return Ok(());
             }
 
-            analyzed.insert(key, value);
+            if let Some(image_path) = &self.image_path {
+                let path = format!("{}.{}.snapshot", image_path, name);
+
+                Self::pack(expanded_path, &path, secret)?;
+
+                let (sha256, size) = digest(&path)?;
+
+                self.snapshots.push(Snapshot {
+                    name,
+                    path,
+                    created,
+                    sha256,
+                    size,
+                    stored: false,
+                });
+
+                return Ok(());
+            }
+
+            return Err("No image to snapshot!".to_string());
         }
 
-        Ok(analyzed)
+        Err("Lab not expanded!".to_string())
     }
 
-    #[inline(always)]
-    fn create_volume(drive_letter: &str, path: &str) -> bool {
-        win_subst::add(drive_letter, path)
+    pub fn find_snapshot(&self, name: &str) -> Result<&Snapshot, String> {
+        match self.snapshots.iter().find(|s| s.name.eq(name)) {
+            Some(snapshot) => Ok(snapshot),
+            None => Err("Snapshot not found!".to_string()),
+        }
     }
 
-    #[inline(always)]
-    fn delete_volume(drive_letter: &str) -> bool {
-        win_subst::del(drive_letter)
+    pub fn remove_snapshot(&mut self, name: &str) -> Result<(), String> {
+        let snapshot = self.find_snapshot(name)?;
+
+        if !snapshot.stored {
+            remove_image(&snapshot.path)?;
+        }
+
+        self.snapshots.retain(|s| !s.name.eq(name));
+
+        Ok(())
+    }
+
+    // snapshot images are the lab's own, nothing else refers to them.
+    // stored ones leave their blobs to the next gc
+    pub fn remove_snapshots(&mut self) -> Result<(), String> {
+        for snapshot in self.snapshots.iter().filter(|s| !s.stored) {
+            remove_image(&snapshot.path)?;
+        }
+
+        self.snapshots.clear();
+
+        Ok(())
+    }
+
+    pub fn verify_snapshots(&self) -> Result<usize, String> {
+        for snapshot in &self.snapshots {
+            match snapshot.stored {
+                true => Store::default().verify(&snapshot.sha256),
+                false => check_digest(&snapshot.path, &snapshot.sha256, snapshot.size),
+            }
+            .map_err(|e| format!("Snapshot {}: {}", snapshot.name, e))?;
+        }
+
+        Ok(self.snapshots.len())
+    }
+
+    pub fn rollback(&self, name: &str, secret: Option<&Secret>) -> Result<(), String> {
+        let snapshot = self.find_snapshot(name)?;
+
+        if let (Some(expanded_path), true) = (&self.expanded_path, snapshot.stored) {
+            // loaded first so a missing tree leaves the expanded folder alone
+            let tree = Store::default().load(&snapshot.sha256)?;
+
+            remove_dir_all(expanded_path).str_result()?;
+
+            return Store::default().checkout(&tree, Path::new(expanded_path));
+        }
+
+        if let Some(expanded_path) = &self.expanded_path {
+            check_digest(&snapshot.path, &snapshot.sha256, snapshot.size)?;
+
+            Self::replace_expanded(&snapshot.path, expanded_path, secret)?;
+
+            // the tree no longer matches the image, so the next repack is a full one
+            if let Some(image_path) = &self.image_path {
+                manifest::remove(image_path)?;
+            }
+
+            return Ok(());
+        }
+
+        Err("Lab not expanded!".to_string())
+    }
+
+    fn pack(expanded_path: &str, path: &str, secret: Option<&Secret>) -> Result<(), String> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .str_result()?;
+
+        let file = match secret {
+            Some(secret) => {
+                let mut archive = Builder::new(EncryptWriter::new(file, secret)?);
+
+                archive.append_dir_all(".", expanded_path).str_result()?;
+                archive.into_inner().str_result()?.finish()?
+            }
+            None => {
+                let mut archive = Builder::new(file);
+
+                archive.append_dir_all(".", expanded_path).str_result()?;
+                archive.into_inner().str_result()?
+            }
+        };
+
+        file.sync_all().str_result()
+    }
+
+    fn replace_expanded(
+        image_path: &str,
+        expanded_path: &str,
+        secret: Option<&Secret>,
+    ) -> Result<(), String> {
+        // opened first so a missing secret leaves the expanded folder alone
+        let mut archive = Archive::new(Self::open_image(image_path, secret)?);
+
+        remove_dir_all(expanded_path).str_result()?;
+
+        archive.unpack(expanded_path).str_result()
+    }
+
+    pub fn expand(&mut self, target_path: String, secret: Option<&Secret>) -> Result<(), String> {
+        if let Some(tree) = &self.tree {
+            Store::default().checkout(&Store::default().load(tree)?, Path::new(&target_path))?;
+
+            self.expanded_path = Some(target_path);
+
+            return Ok(());
+        }
+
+        if let Some(image_path) = &self.image_path {
+            match self.layers.is_empty() {
+                true => {
+                    let mut archive = Archive::new(Self::open_image(image_path, secret)?);
+
+                    archive.unpack(&target_path).str_result()?;
+                }
+                false => {
+                    create_dir_all(&target_path).str_result()?;
+
+                    for reader in self.open_layers(secret, true)? {
+                        layer::unpack(reader, Path::new(&target_path))?;
+                    }
+                }
+            }
+
+            self.expanded_path = Some(target_path);
+
+            return self.record_manifest();
+        }
+
+        Err("No image to expand!".to_string())
+    }
+
+    // lets the next repack skip whatever was not touched since
+    fn record_manifest(&self) -> Result<(), String> {
+        // layered labs diff against the layers below instead
+        if !self.layers.is_empty() {
+            return Ok(());
+        }
+
+        if let (Some(image_path), Some(expanded_path), Some(image_sha256)) =
+            (&self.image_path, &self.expanded_path, &self.image_sha256)
+        {
+            return manifest::record(image_path, expanded_path, image_sha256);
+        }
+
+        Ok(())
+    }
+
+    // path is where the lab lives or is about to be expanded to
+    pub fn preflight(&self, path: &str) -> Result<(), Vec<String>> {
+        match &self.config.requires {
+            Some(requires) => preflight::check(requires, Path::new(path)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn encryption(&self) -> Result<Option<KeyKind>, String> {
+        match &self.image_path {
+            Some(image_path) => crypto::detect(image_path),
+            None => Ok(None),
+        }
+    }
+
+    // a mistyped passphrase would otherwise seal the repacked image under a new one
+    pub fn check_secret(&self, secret: &Secret) -> Result<(), String> {
+        match &self.image_path {
+            Some(image_path) => Self::open_image(image_path, Some(secret)).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn snapshot_encryption(&self, name: &str) -> Result<Option<KeyKind>, String> {
+        match self.find_snapshot(name)? {
+            snapshot if snapshot.stored => Ok(None),
+            snapshot => crypto::detect(&snapshot.path),
+        }
+    }
+
+    fn lower(
+        &self,
+        image_path: &str,
+        secret: Option<&Secret>,
+    ) -> Result<HashMap<String, layer::Lower>, String> {
+        let layers = self.layers.iter().map(|l| l.sha256.clone()).collect();
+
+        layer::cached_index(image_path, layers, secret, || {
+            self.open_layers(secret, false)
+        })
+    }
+
+    // the shared layers bottom first, then the lab's own image on top when asked for
+    fn open_layers(
+        &self,
+        secret: Option<&Secret>,
+        with_top: bool,
+    ) -> Result<Vec<Box<dyn Read>>, String> {
+        let mut readers = Vec::new();
+
+        for layer in &self.layers {
+            readers.push(Self::open_image(&layer.path, secret)?);
+        }
+
+        if let (Some(image_path), true) = (&self.image_path, with_top) {
+            readers.push(Self::open_image(image_path, secret)?);
+        }
+
+        Ok(readers)
+    }
+
+    // encrypted images are decrypted while being read, never as a whole
+    fn open_image(image_path: &str, secret: Option<&Secret>) -> Result<Box<dyn Read>, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(image_path)
+            .str_result()?;
+
+        match (crypto::detect(image_path)?, secret) {
+            (None, _) => Ok(Box::new(file)),
+            (Some(_), Some(secret)) => Ok(Box::new(DecryptReader::new(file, secret)?)),
+            (Some(_), None) => Err("Image is encrypted!".to_string()),
+        }
+    }
+
+    pub fn mount(&mut self, drive_letter: String) -> Result<(), String> {
+        match &self.drive_letter {
+            Some(d) => {
+                if !d.eq(&drive_letter) {
+                    self.unmount()?;
+                } else {
+                    return Ok(());
+                }
+            }
+            None => {}
+        };
+
+        if let Some(expanded_path) = &self.expanded_path {
+            match Self::create_volume((drive_letter.clone() + ":").as_str(), &expanded_path) {
+                true => {
+                    self.drive_letter = Some(drive_letter);
+
+                    return Ok(());
+                }
+                false => return Err("Failed to create volume!".to_string()),
+            }
+        }
+
+        Err("Lab not expanded!".to_string())
+    }
+
+    pub fn unmount(&mut self) -> Result<(), String> {
+        if let Some(drive_letter) = &self.drive_letter {
+            match Self::delete_volume((drive_letter.clone() + ":").as_str()) {
+                true => {
+                    self.drive_letter = None;
+
+                    return Ok(());
+                }
+                false => return Err("Failed to delete volume!".to_string()),
+            }
+        }
+
+        Err("Lab not mounted!".to_string())
+    }
+
+    pub fn run(
+        &self,
+        app: &str,
+        args: Option<Vec<String>>,
+        invocation: &Invocation,
+    ) -> Result<Child, String> {
+        let a = self.resolve(self.host_app(app)?, invocation);
+
+        let all_args = {
+            let mut all_args = a.args.clone();
+
+            match args {
+                Some(mut a) => {
+                    all_args.append(&mut a);
+                }
+                None => {}
+            }
+
+            all_args
+        };
+
+        if let Some(sandbox) = &a.app.sandbox {
+            return self.run_sandboxed(&a, sandbox, all_args);
+        }
+
+        if let Some(drive_letter) = &self.drive_letter {
+            // run app and return handle
+            let child = Command::new(drive_letter.clone() + ":" + &a.command)
+                .env_clear()
+                .current_dir(drive_letter.clone() + ":" + &a.work_dir)
+                .envs(self.analyze_envs(&a.envs, drive_letter)?)
+                .args(all_args)
+                .spawn()
+                .str_result()?;
+
+            return Ok(child);
+        }
+
+        Err("Lab not mounted".to_string())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn run_sandboxed(
+        &self,
+        app: &Resolved,
+        sandbox: &Sandbox,
+        args: Vec<String>,
+    ) -> Result<Child, String> {
+        if let Some(expanded_path) = &self.expanded_path {
+            // a lab mounted at the root leaves its paths as they are
+            let mount_point = match sandbox.mount_point.trim_matches('/') {
+                "" => String::new(),
+                mount_point => "/".to_string() + mount_point,
+            };
+
+            let mut command =
+                Command::new(mount_point.clone() + "/" + app.command.trim_start_matches('/'));
+
+            command
+                .env_clear()
+                .envs(self.analyze_envs(&app.envs, &mount_point)?)
+                .args(args);
+
+            sandbox::confine(&mut command, sandbox, expanded_path, &app.work_dir)?;
+
+            return command.spawn().str_result();
+        }
+
+        Err("Lab not expanded!".to_string())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn run_sandboxed(
+        &self,
+        _app: &Resolved,
+        _sandbox: &Sandbox,
+        _args: Vec<String>,
+    ) -> Result<Child, String> {
+        Err("Sandbox is only supported on Linux!".to_string())
+    }
+
+    pub fn pre_run(&self, app: &str, invocation: &Invocation) -> Result<(), String> {
+        let a = self.host_app(app)?;
+
+        self.run_hooks(a, self.config.pre_run.iter().chain(&a.pre_run), invocation)
+    }
+
+    pub fn post_run(&self, app: &str, invocation: &Invocation) -> Result<(), String> {
+        let a = self.host_app(app)?;
+
+        self.run_hooks(
+            a,
+            a.post_run.iter().chain(&self.config.post_run),
+            invocation,
+        )
+    }
+
+    pub fn prepare(
+        &self,
+        app: &str,
+        params: Vec<(String, String)>,
+        profile: Option<String>,
+    ) -> Result<Invocation, String> {
+        let profile = match profile.or(self.config.default_profile.clone()) {
+            Some(profile) => match self.config.profiles.contains_key(&profile) {
+                true => Some(profile),
+                false => return Err("Profile not found!".to_string()),
+            },
+            None => None,
+        };
+
+        Ok(Invocation {
+            params: self.resolve_params(app, params)?,
+            profile,
+        })
+    }
+
+    fn resolve_params(
+        &self,
+        app: &str,
+        given: Vec<(String, String)>,
+    ) -> Result<HashMap<String, String>, String> {
+        let a = self.find_app(app)?;
+
+        let mut resolved: HashMap<String, String> = HashMap::new();
+
+        for (key, value) in given {
+            if !a.params.iter().any(|p| p.name.eq(&key)) {
+                return Err(format!("Unknown parameter: {}", key));
+            }
+
+            resolved.insert(key, value);
+        }
+
+        for param in &a.params {
+            let value = match resolved.get(&param.name) {
+                Some(value) => value.clone(),
+                None => match &param.default {
+                    Some(default) => default.clone(),
+                    None if param.required => {
+                        return Err(format!("Missing required parameter: {}", param.name));
+                    }
+                    None => String::new(),
+                },
+            };
+
+            let valid = match param.kind {
+                ParamKind::String => true,
+                ParamKind::Int => value.is_empty() || value.parse::<i64>().is_ok(),
+                ParamKind::Path => value.is_empty() || self.param_path(&value)?.exists(),
+                ParamKind::Enum => value.is_empty() || param.values.contains(&value),
+            };
+
+            if !valid {
+                return Err(format!(
+                    "Invalid {} value for parameter {}: {}",
+                    param.kind.as_str(),
+                    param.name,
+                    value
+                ));
+            }
+
+            resolved.insert(param.name.clone(), value);
+        }
+
+        Ok(resolved)
+    }
+
+    // a path parameter names something inside the lab, unless it carries a drive of its own
+    fn param_path(&self, value: &str) -> Result<PathBuf, String> {
+        if value.contains(':') {
+            return Ok(PathBuf::from(value));
+        }
+
+        match &self.expanded_path {
+            Some(expanded_path) => {
+                Ok(Path::new(expanded_path).join(value.trim_start_matches(['/', '\\'])))
+            }
+            None => Err("Lab not expanded!".to_string()),
+        }
+    }
+
+    pub fn declares(&self, app: &str, param: &str) -> Result<bool, String> {
+        Ok(self.find_app(app)?.params.iter().any(|p| p.name.eq(param)))
+    }
+
+    pub fn group_order(&self, group: &str) -> Result<(Vec<&Member>, String), String> {
+        let g = match self.config.groups.iter().find(|g| g.name.eq(group)) {
+            Some(g) => g,
+            None => return Err("Group not found!".to_string()),
+        };
+
+        for m in &g.members {
+            self.find_app(&m.app)?;
+
+            for d in &m.depends_on {
+                if !g.members.iter().any(|o| o.app.eq(d)) {
+                    return Err(format!("Unknown dependency: {} -> {}", m.app, d));
+                }
+            }
+        }
+
+        let mut ordered: Vec<&Member> = Vec::new();
+
+        while ordered.len() < g.members.len() {
+            let next = g.members.iter().find(|m| {
+                !ordered.iter().any(|o| o.app.eq(&m.app))
+                    && m.depends_on
+                        .iter()
+                        .all(|d| ordered.iter().any(|o| o.app.eq(d)))
+            });
+
+            match next {
+                Some(m) => ordered.push(m),
+                None => return Err("Dependency cycle in group!".to_string()),
+            }
+        }
+
+        let main = match &g.main {
+            Some(main) => {
+                if !ordered.iter().any(|m| m.app.eq(main)) {
+                    return Err("Main app is not a group member!".to_string());
+                }
+
+                main.clone()
+            }
+            None => match ordered.last() {
+                Some(m) => m.app.clone(),
+                None => return Err("Group is empty!".to_string()),
+            },
+        };
+
+        Ok((ordered, main))
+    }
+
+    pub fn wait_ready(
+        &self,
+        app: &str,
+        ready: &Ready,
+        child: &mut Child,
+        invocation: &Invocation,
+    ) -> Result<(), String> {
+        let (mnt, work_dir) = self.host_location(&self.resolve(self.find_app(app)?, invocation))?;
+
+        if let Some(delay_ms) = ready.delay_ms {
+            thread::sleep(Duration::from_millis(delay_ms));
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(ready.timeout_ms);
+
+        loop {
+            if child.try_wait().str_result()?.is_some() {
+                return Err(format!("App exited before becoming ready: {}", app));
+            }
+
+            let port_ready = match ready.port {
+                Some(port) => TcpStream::connect_timeout(
+                    &SocketAddr::from(([127, 0, 0, 1], port)),
+                    Duration::from_millis(500),
+                )
+                .is_ok(),
+                None => true,
+            };
+
+            let file_ready = match &ready.file {
+                Some(file) => work_dir
+                    .join(self.substitute(file, invocation).replace("$mnt$", &mnt))
+                    .exists(),
+                None => true,
+            };
+
+            if port_ready && file_ready {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!("App did not become ready: {}", app));
+            }
+
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    fn app_mut(&mut self, app: &str) -> Result<&mut App, String> {
+        match self.config.apps.iter_mut().find(|a| a.name.eq(app)) {
+            Some(a) => Ok(a),
+            None => Err("App not found!".to_string()),
+        }
+    }
+
+    pub fn add_app(&mut self, app: String, changes: AppChanges) -> Result<(), String> {
+        if self.config.apps.iter().any(|a| a.name.eq(&app)) {
+            return Err("App with similar name exists!".to_string());
+        }
+
+        self.config.apps.push(App {
+            name: app,
+            command: match changes.command {
+                Some(command) => command,
+                None => return Err("No command given!".to_string()),
+            },
+            args: changes.args.unwrap_or_default(),
+            work_dir: changes.work_dir.unwrap_or("\\".to_string()),
+            ..Default::default()
+        });
+
+        Ok(())
+    }
+
+    pub fn edit_app(&mut self, app: &str, changes: AppChanges) -> Result<(), String> {
+        let a = self.app_mut(app)?;
+
+        if let Some(new_name) = changes.name {
+            a.name = new_name;
+        }
+
+        if let Some(command) = changes.command {
+            a.command = command;
+        }
+
+        if let Some(work_dir) = changes.work_dir {
+            a.work_dir = work_dir;
+        }
+
+        if let Some(args) = changes.args {
+            a.args = args;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_env(&mut self, app: &str, key: String, value: String) -> Result<(), String> {
+        let a = self.app_mut(app)?;
+
+        match a.envs.iter_mut().find(|e| e.key.eq(&key)) {
+            Some(env) => env.value = value,
+            None => a.envs.push(Env { key, value }),
+        }
+
+        Ok(())
+    }
+
+    pub fn unset_env(&mut self, app: &str, key: &str) -> Result<(), String> {
+        let a = self.app_mut(app)?;
+
+        match a.envs.iter().position(|e| e.key.eq(key)) {
+            Some(index) => {
+                a.envs.remove(index);
+
+                Ok(())
+            }
+            None => Err("Env not found!".to_string()),
+        }
+    }
+
+    pub fn remove_app(&mut self, app: &str) -> Result<(), String> {
+        let index = self.config.apps.iter().position(|a| a.name.eq(app));
+
+        match index {
+            Some(index) => {
+                self.config.apps.remove(index);
+                Ok(())
+            }
+            None => Err("App not found!".to_string()),
+        }
+    }
+
+    pub fn default_app(&self) -> Result<String, String> {
+        if let Some(default_app) = &self.config.default_app {
+            return Ok(default_app.clone());
+        }
+
+        match self.config.apps.as_slice() {
+            [app] => Ok(app.name.clone()),
+            _ => Err("No app chosen and lab has no default app!".to_string()),
+        }
+    }
+
+    fn host_app(&self, app: &str) -> Result<&App, String> {
+        let a = self.find_app(app)?;
+
+        match a.is_available() {
+            true => Ok(a),
+            false => Err("App is not available on this platform!".to_string()),
+        }
+    }
+
+    fn find_app(&self, app: &str) -> Result<&App, String> {
+        let apps = &self.config.apps;
+
+        let names = |a: &'_ App| {
+            let mut names = vec![a.name.clone()];
+            names.extend(a.aliases.iter().cloned());
+
+            names
+        };
+
+        if let Some(a) = apps.iter().find(|a| names(a).iter().any(|n| n.eq(app))) {
+            return Ok(a);
+        }
+
+        let prefixed: Vec<&App> = apps
+            .iter()
+            .filter(|a| names(a).iter().any(|n| n.starts_with(app)))
+            .collect();
+
+        match prefixed.as_slice() {
+            [a] => return Ok(a),
+            [] => {}
+            _ => {
+                let candidates: Vec<&str> = prefixed.iter().map(|a| a.name.as_str()).collect();
+
+                return Err(format!(
+                    "App name is ambiguous! Candidates: {}",
+                    candidates.join(", ")
+                ));
+            }
+        }
+
+        let suggestion = apps
+            .iter()
+            .flat_map(names)
+            .map(|n| (distance(&n, app), n))
+            .filter(|(d, n)| *d <= 2.max(n.len() / 3))
+            .min_by_key(|(d, _)| *d);
+
+        match suggestion {
+            Some((_, n)) => Err(format!("App not found! Did you mean {}?", n)),
+            None => Err("App not found!".to_string()),
+        }
+    }
+
+    fn run_hooks<'a>(
+        &self,
+        app: &App,
+        hooks: impl Iterator<Item = &'a String>,
+        invocation: &Invocation,
+    ) -> Result<(), String> {
+        let app = self.resolve(app, invocation);
+
+        let (mnt, work_dir) = self.host_location(&app)?;
+
+        let envs = self.analyze_envs(&app.envs, &mnt)?;
+
+        for hook in hooks {
+            let hook = self.substitute(hook, invocation).replace("$mnt$", &mnt);
+
+            let status = Self::shell(&hook)
+                .env_clear()
+                .current_dir(&work_dir)
+                .envs(
+                    HOOK_ENVS
+                        .iter()
+                        .filter_map(|k| env::var_os(k).map(|v| (k, v))),
+                )
+                .envs(&envs)
+                .status()
+                .str_result()?;
+
+            if !status.success() {
+                return Err(format!("Hook failed: {}", hook));
+            }
+        }
+
+        Ok(())
+    }
+
+    // hooks and readiness checks always run on the host, outside of any sandbox
+    fn host_location(&self, app: &Resolved) -> Result<(String, PathBuf), String> {
+        match &app.app.sandbox {
+            Some(_) => match &self.expanded_path {
+                Some(expanded_path) => Ok((
+                    expanded_path.clone(),
+                    Path::new(expanded_path).join(app.work_dir.trim_start_matches(['/', '\\'])),
+                )),
+                None => Err("Lab not expanded!".to_string()),
+            },
+            None => match &self.drive_letter {
+                Some(drive_letter) => Ok((
+                    drive_letter.clone(),
+                    PathBuf::from(drive_letter.clone() + ":" + &app.work_dir),
+                )),
+                None => Err("Lab not mounted".to_string()),
+            },
+        }
+    }
+
+    #[cfg(windows)]
+    #[inline(always)]
+    fn shell(line: &str) -> Command {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(line);
+
+        command
+    }
+
+    #[cfg(not(windows))]
+    #[inline(always)]
+    fn shell(line: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(line);
+
+        command
+    }
+
+    fn resolve<'a>(&self, app: &'a App, invocation: &Invocation) -> Resolved<'a> {
+        let target = app.host_target();
+        let profile = invocation
+            .profile
+            .as_ref()
+            .and_then(|p| self.config.profiles.get(p));
+        let profile_app = profile.and_then(|p| p.apps.get(&app.name));
+
+        // each layer overrides the envs of the ones before it
+        let mut envs = self.config.envs.clone();
+        overlay(&mut envs, &app.envs);
+
+        if let Some(target) = target {
+            overlay(&mut envs, &target.envs);
+        }
+
+        if let Some(profile) = profile {
+            overlay(&mut envs, &profile.envs);
+        }
+
+        if let Some(profile_app) = profile_app {
+            overlay(&mut envs, &profile_app.envs);
+        }
+
+        for env in &mut envs {
+            env.value = self.substitute(&env.value, invocation);
+        }
+
+        let command = target
+            .and_then(|t| t.command.as_ref())
+            .unwrap_or(&app.command);
+        let args = profile_app
+            .and_then(|p| p.args.as_ref())
+            .or(target.and_then(|t| t.args.as_ref()))
+            .unwrap_or(&app.args);
+        let work_dir = target
+            .and_then(|t| t.work_dir.as_ref())
+            .unwrap_or(&app.work_dir);
+
+        Resolved {
+            app,
+            command: self.substitute(command, invocation),
+            args: args
+                .iter()
+                .map(|a| self.substitute(a, invocation))
+                .collect(),
+            work_dir: self.substitute(work_dir, invocation),
+            envs,
+        }
+    }
+
+    fn substitute(&self, value: &str, invocation: &Invocation) -> String {
+        let mut value = value.to_string();
+
+        for (key, var) in &self.config.vars {
+            value = value.replace(&format!("$var:{}$", key), var);
+        }
+
+        for (key, param) in &invocation.params {
+            value = value.replace(&format!("$param:{}$", key), param);
+        }
+
+        value
+    }
+
+    fn analyze_envs(&self, envs: &[Env], mnt: &str) -> Result<HashMap<String, String>, String> {
+        let mut analyzed: HashMap<String, String> = HashMap::new();
+
+        for env in envs {
+            let (key, mut value) = (env.key.clone(), env.value.clone());
+
+            // key = key.replace("$mnt$", &drive_letter);
+            if value.eq("$sm$") {
+                value = env::var(&key).str_result()?;
+            } else {
+                value = value.replace("$mnt$", mnt);
+            }
+
+            analyzed.insert(key, value);
+        }
+
+        Ok(analyzed)
+    }
+
+    #[inline(always)]
+    fn create_volume(drive_letter: &str, path: &str) -> bool {
+        win_subst::add(drive_letter, path)
+    }
+
+    #[inline(always)]
+    fn delete_volume(drive_letter: &str) -> bool {
+        win_subst::del(drive_letter)
+    }
+}
+
+fn overlay(envs: &mut Vec<Env>, layer: &[Env]) {
+    for env in layer {
+        match envs.iter_mut().find(|e| e.key.eq(&env.key)) {
+            Some(e) => e.value = env.value.clone(),
+            None => envs.push(env.clone()),
+        }
+    }
+}
+
+// already gone is as good as removed
+fn remove_image(path: &str) -> Result<(), String> {
+    match remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(format!("{}: {}", path, e)),
+        _ => Ok(()),
+    }
+}
+
+fn check_digest(path: &str, expected: &str, expected_size: u64) -> Result<(), String> {
+    // a size mismatch is caught without reading the whole image
+    let size = metadata(path).str_result()?.len();
+
+    if size != expected_size {
+        return Err(format!(
+            "Image size mismatch! Expected {} bytes, found {}",
+            expected_size, size
+        ));
+    }
+
+    let (sha256, _) = digest(path)?;
+
+    if !sha256.eq(expected) {
+        return Err(format!(
+            "Image checksum mismatch! Expected {}, found {}",
+            expected, sha256
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn digest(path: &str) -> Result<(String, u64), String> {
+    let mut file = File::open(path).str_result()?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size: u64 = 0;
+
+    loop {
+        let read = file.read(&mut buffer).str_result()?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok((sha256, size))
+}
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+
+            row[j + 1] = match ca.eq(cb) {
+                true => previous,
+                false => 1 + previous.min(row[j]).min(row[j + 1]),
+            };
+
+            previous = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, write};
+
+    use super::*;
+    use crate::testing::Scratch;
+
+    // a lab expanded into the scratch folder, configured from toml
+    fn lab(scratch: &Scratch, source: &str) -> Lab {
+        let mut lab = Lab::from_expanded(scratch.path("lab"));
+        lab.config = validate::check("lab.toml", source).unwrap();
+
+        lab
+    }
+
+    const GROUP: &str = r#"
+name = "lab"
+
+[[apps]]
+name = "db"
+command = "db"
+
+[[apps]]
+name = "server"
+command = "server"
+
+[[apps]]
+name = "client"
+command = "client"
+
+[[groups]]
+name = "stack"
+members = [
+    { app = "client", depends_on = ["server"] },
+    { app = "server", depends_on = ["db"] },
+    { app = "db" },
+]
+"#;
+
+    fn order(lab: &Lab, group: &str) -> Result<(Vec<String>, String), String> {
+        let (members, main) = lab.group_order(group)?;
+
+        Ok((members.iter().map(|m| m.app.clone()).collect(), main))
+    }
+
+    #[test]
+    fn base_layers_are_held_to_the_signing_policy() {
+        let scratch = Scratch::new();
+        let key = scratch.path("key");
+        let signing = Signing {
+            trusted_keys: vec![signature::generate(&key).unwrap()],
+            required: true,
+        };
+
+        let top = scratch.write("top.tar", "top");
+        let base = scratch.write("base.tar", "base");
+
+        let mut lab = Lab::from_image(top.clone());
+        lab.add_layer(base.clone()).unwrap();
+
+        // a signed top alone vouches for nothing below it
+        signature::sign(&top, &digest(&top).unwrap().0, &key).unwrap();
+        assert!(lab.verify_signature(&signing).is_err());
+
+        lab.sign_image(&key).unwrap();
+        assert!(lab.verify_signature(&signing).is_ok());
+
+        write(&base, "tampered").unwrap();
+        assert!(lab.verify_signature(&signing).is_err());
+    }
+
+    #[test]
+    fn groups_start_their_dependencies_first() {
+        let scratch = Scratch::new();
+        let lab = lab(&scratch, GROUP);
+
+        assert_eq!(
+            order(&lab, "stack").unwrap(),
+            (
+                vec!["db".to_string(), "server".to_string(), "client".to_string()],
+                "client".to_string()
+            )
+        );
+        assert!(order(&lab, "missing").is_err());
+    }
+
+    #[test]
+    fn dependency_cycles_are_refused() {
+        let scratch = Scratch::new();
+        let lab = lab(
+            &scratch,
+            &GROUP.replace(
+                r#"{ app = "db" }"#,
+                r#"{ app = "db", depends_on = ["client"] }"#,
+            ),
+        );
+
+        assert_eq!(
+            order(&lab, "stack").unwrap_err(),
+            "Dependency cycle in group!"
+        );
+    }
+
+    #[test]
+    fn main_apps_belong_to_their_group() {
+        let scratch = Scratch::new();
+        let mut lab = lab(&scratch, GROUP);
+
+        lab.config.groups[0].main = Some("server".to_string());
+        assert_eq!(order(&lab, "stack").unwrap().1, "server");
+
+        lab.config.groups[0].main = Some("other".to_string());
+        assert!(order(&lab, "stack").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hooks_wrap_the_app_with_its_envs() {
+        let scratch = Scratch::new();
+        scratch.write("lab/work/.keep", "");
+
+        let lab = lab(
+            &scratch,
+            r#"
+name = "lab"
+pre_run = ["echo lab-pre >> $mnt$/log"]
+post_run = ["echo lab-post $GREETING >> $mnt$/log"]
+
+[[apps]]
+name = "tool"
+command = "tool"
+work_dir = "work"
+envs = [{ key = "GREETING", value = "hello" }]
+pre_run = ["echo app-pre $GREETING $(basename $(pwd)) >> $mnt$/log"]
+post_run = ["echo app-post >> $mnt$/log", "false", "echo unreachable >> $mnt$/log"]
+sandbox = {}
+"#,
+        );
+
+        let invocation = lab.prepare("tool", Vec::new(), None).unwrap();
+
+        lab.pre_run("tool", &invocation).unwrap();
+        assert_eq!(
+            lab.post_run("tool", &invocation).unwrap_err(),
+            "Hook failed: false"
+        );
+
+        assert_eq!(
+            read_to_string(scratch.root.join("lab/log")).unwrap(),
+            "lab-pre\napp-pre hello work\napp-post\n"
+        );
+    }
+
+    #[test]
+    fn apps_are_found_by_alias_and_unique_prefix() {
+        let scratch = Scratch::new();
+        let mut lab = lab(
+            &scratch,
+            r#"
+name = "lab"
+
+[[apps]]
+name = "server"
+aliases = ["srv"]
+command = "server"
+
+[[apps]]
+name = "service"
+command = "service"
+
+[[apps]]
+name = "client"
+command = "client"
+"#,
+        );
+
+        let found = |lab: &Lab, app| lab.find_app(app).map(|a| a.name.clone());
+
+        assert_eq!(found(&lab, "srv").unwrap(), "server");
+        assert_eq!(found(&lab, "serve").unwrap(), "server");
+        assert_eq!(found(&lab, "cl").unwrap(), "client");
+        assert_eq!(
+            found(&lab, "ser").unwrap_err(),
+            "App name is ambiguous! Candidates: server, service"
+        );
+        assert_eq!(
+            found(&lab, "clinet").unwrap_err(),
+            "App not found! Did you mean client?"
+        );
+        assert_eq!(found(&lab, "database").unwrap_err(), "App not found!");
+
+        assert!(lab.default_app().is_err());
+        lab.config.default_app = Some("client".to_string());
+        assert_eq!(lab.default_app().unwrap(), "client");
+    }
+
+    #[test]
+    fn params_are_checked_and_substituted() {
+        let scratch = Scratch::new();
+        scratch.write("lab/data/input.txt", "");
+
+        let lab = lab(
+            &scratch,
+            r#"
+name = "lab"
+
+[[apps]]
+name = "tool"
+command = "tool"
+args = ["--port", "$param:port$", "--mode=$param:mode$", "$param:input$"]
+envs = [{ key = "MODE", value = "$param:mode$" }]
+params = [
+    { name = "port", type = "int", default = "8080" },
+    { name = "mode", type = "enum", values = ["fast", "safe"], required = true },
+    { name = "input", type = "path" },
+]
+"#,
+        );
+
+        let prepare = |given: &[(&str, &str)]| {
+            let given = given
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+
+            lab.prepare("tool", given, None)
+        };
+
+        assert_eq!(
+            prepare(&[]).err().as_deref(),
+            Some("Missing required parameter: mode")
+        );
+        assert_eq!(
+            prepare(&[("mode", "fast"), ("other", "1")])
+                .err()
+                .as_deref(),
+            Some("Unknown parameter: other")
+        );
+        assert_eq!(
+            prepare(&[("mode", "slow")]).err().as_deref(),
+            Some("Invalid enum value for parameter mode: slow")
+        );
+        assert_eq!(
+            prepare(&[("mode", "fast"), ("port", "http")])
+                .err()
+                .as_deref(),
+            Some("Invalid int value for parameter port: http")
+        );
+        assert!(prepare(&[("mode", "fast"), ("input", "data/missing.txt")]).is_err());
+
+        let invocation = prepare(&[("mode", "safe"), ("input", "data/input.txt")]).unwrap();
+        let resolved = lab.resolve(&lab.config.apps[0], &invocation);
+
+        assert_eq!(
+            resolved.args,
+            ["--port", "8080", "--mode=safe", "data/input.txt"]
+        );
+        assert_eq!(resolved.envs[0].value, "safe");
+    }
+
+    #[test]
+    fn apps_and_envs_are_edited_in_place() {
+        let scratch = Scratch::new();
+        let mut lab = lab(&scratch, "name = \"lab\"\napps = []\n");
+
+        let changes = |command: Option<&str>| AppChanges {
+            command: command.map(str::to_string),
+            args: Some(vec!["--serve".to_string()]),
+            ..Default::default()
+        };
+
+        assert!(lab.add_app("server".to_string(), changes(None)).is_err());
+        lab.add_app("server".to_string(), changes(Some("server")))
+            .unwrap();
+        lab.add_app("client".to_string(), changes(Some("client")))
+            .unwrap();
+        assert!(lab
+            .add_app("server".to_string(), changes(Some("other")))
+            .is_err());
+
+        lab.set_env("server", "PORT".to_string(), "80".to_string())
+            .unwrap();
+        lab.set_env("server", "PORT".to_string(), "8080".to_string())
+            .unwrap();
+        assert_eq!(lab.config.apps[0].envs.len(), 1);
+        assert_eq!(lab.config.apps[0].envs[0].value, "8080");
+
+        lab.unset_env("server", "PORT").unwrap();
+        assert!(lab.unset_env("server", "PORT").is_err());
+        assert!(lab
+            .set_env("missing", "A".to_string(), "1".to_string())
+            .is_err());
+
+        lab.remove_app("client").unwrap();
+        assert!(lab.remove_app("client").is_err());
+
+        lab.edit_app(
+            "server",
+            AppChanges {
+                name: Some("api".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(lab.config.apps[0].name, "api");
+        assert_eq!(lab.config.apps[0].args, ["--serve"]);
+        assert!(validate::check_config(&lab.config).is_ok());
+
+        // an edit leaving the config broken is caught before it is written
+        lab.add_app("web".to_string(), changes(Some("web")))
+            .unwrap();
+        lab.edit_app(
+            "web",
+            AppChanges {
+                name: Some("api".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(validate::check_config(&lab.config).is_err());
+    }
+
+    #[test]
+    fn lab_vars_and_envs_reach_every_app() {
+        let scratch = Scratch::new();
+        let lab = lab(
+            &scratch,
+            r#"
+name = "lab"
+envs = [{ key = "HOME", value = "$var:root$/home" }, { key = "LANG", value = "C" }]
+
+[vars]
+root = "opt/tool"
+
+[[apps]]
+name = "tool"
+command = "$var:root$/bin/tool"
+args = ["--config", "$var:root$/tool.conf"]
+work_dir = "$var:root$"
+envs = [{ key = "LANG", value = "en_US" }]
+"#,
+        );
+
+        let invocation = lab.prepare("tool", Vec::new(), None).unwrap();
+        let resolved = lab.resolve(&lab.config.apps[0], &invocation);
+
+        assert_eq!(resolved.command, "opt/tool/bin/tool");
+        assert_eq!(resolved.args, ["--config", "opt/tool/tool.conf"]);
+        assert_eq!(resolved.work_dir, "opt/tool");
+
+        let envs: Vec<(&str, &str)> = resolved
+            .envs
+            .iter()
+            .map(|e| (e.key.as_str(), e.value.as_str()))
+            .collect();
+
+        assert_eq!(envs, [("HOME", "opt/tool/home"), ("LANG", "en_US")]);
+    }
+
+    #[test]
+    fn host_targets_override_the_app() {
+        let scratch = Scratch::new();
+        let elsewhere = match env::consts::OS {
+            "solaris" => "netbsd",
+            _ => "solaris",
+        };
+
+        let lab = lab(
+            &scratch,
+            &format!(
+                r#"
+name = "lab"
+
+[[apps]]
+name = "tool"
+command = "tool"
+args = ["--plain"]
+target.{here} = {{ command = "tool-here", envs = [{{ key = "MODE", value = "here" }}] }}
+target.{elsewhere} = {{ command = "tool-elsewhere", args = [] }}
+
+[[apps]]
+name = "foreign"
+target.{elsewhere} = {{ command = "foreign" }}
+"#,
+                here = env::consts::OS,
+                elsewhere = elsewhere
+            ),
+        );
+
+        let invocation = lab.prepare("tool", Vec::new(), None).unwrap();
+        let resolved = lab.resolve(lab.host_app("tool").unwrap(), &invocation);
+
+        assert_eq!(resolved.command, "tool-here");
+        assert_eq!(resolved.args, ["--plain"]);
+        assert_eq!(resolved.envs[0].value, "here");
+
+        assert!(!lab.config.apps[1].is_available());
+        assert_eq!(
+            lab.host_app("foreign").err(),
+            Some("App is not available on this platform!".to_string())
+        );
+    }
+
+    #[test]
+    fn profiles_overlay_envs_and_args() {
+        let scratch = Scratch::new();
+        let lab = lab(
+            &scratch,
+            r#"
+name = "lab"
+default_profile = "dev"
+
+[[apps]]
+name = "tool"
+command = "tool"
+args = ["--plain"]
+envs = [{ key = "LEVEL", value = "trace" }]
+
+[profiles.dev]
+envs = [{ key = "LEVEL", value = "debug" }]
+
+[profiles.release]
+envs = [{ key = "LEVEL", value = "info" }]
+apps.tool = { args = ["--fast"], envs = [{ key = "LEVEL", value = "warn" }] }
+"#,
+        );
+
+        let resolved = |profile: Option<&str>| {
+            let invocation = lab
+                .prepare("tool", Vec::new(), profile.map(str::to_string))
+                .unwrap();
+            let resolved = lab.resolve(&lab.config.apps[0], &invocation);
+
+            (resolved.args, resolved.envs[0].value.clone())
+        };
+
+        assert_eq!(
+            resolved(None),
+            (vec!["--plain".to_string()], "debug".to_string())
+        );
+        assert_eq!(
+            resolved(Some("release")),
+            (vec!["--fast".to_string()], "warn".to_string())
+        );
+        assert_eq!(
+            lab.prepare("tool", Vec::new(), Some("staging".to_string()))
+                .err(),
+            Some("Profile not found!".to_string())
+        );
+    }
+
+    #[test]
+    fn snapshots_roll_the_lab_back() {
+        let scratch = Scratch::new();
+        let a = scratch.write("lab/a.txt", "first");
+
+        let mut lab = Lab::from_image(scratch.path("lab.tar"));
+        lab.expanded_path = Some(scratch.path("lab"));
+
+        lab.snapshot("one".to_string(), None).unwrap();
+        assert!(lab.snapshot("one".to_string(), None).is_err());
+        assert!(lab.snapshot("a/b".to_string(), None).is_err());
+
+        write(&a, "second").unwrap();
+        let b = scratch.write("lab/b.txt", "new");
+
+        lab.rollback("one", None).unwrap();
+        assert_eq!(read_to_string(&a).unwrap(), "first");
+        assert!(!Path::new(&b).exists());
+        assert_eq!(lab.verify_snapshots().unwrap(), 1);
+
+        // a damaged snapshot leaves the lab as it is
+        let path = lab.find_snapshot("one").unwrap().path.clone();
+        write(&path, "tampered").unwrap();
+        write(&a, "second").unwrap();
+
+        assert!(lab.verify_snapshots().is_err());
+        assert!(lab.rollback("one", None).is_err());
+        assert_eq!(read_to_string(&a).unwrap(), "second");
+
+        lab.remove_snapshot("one").unwrap();
+        assert!(!Path::new(&path).exists());
+        assert!(lab.rollback("one", None).is_err());
+    }
+
+    #[test]
+    fn encrypted_snapshots_need_the_secret() {
+        let scratch = Scratch::new();
+        let a = scratch.write("lab/a.txt", "first");
+        let secret = Secret::Passphrase("passphrase".to_string());
+
+        let mut lab = Lab::from_image(scratch.path("lab.tar"));
+        lab.expanded_path = Some(scratch.path("lab"));
+
+        lab.snapshot("sealed".to_string(), Some(&secret)).unwrap();
+        write(&a, "second").unwrap();
+
+        assert!(lab.rollback("sealed", None).is_err());
+        assert_eq!(read_to_string(&a).unwrap(), "second");
+
+        lab.rollback("sealed", Some(&secret)).unwrap();
+        assert_eq!(read_to_string(&a).unwrap(), "first");
     }
 }
@@ -0,0 +1,284 @@
+use std::{
+    fs::OpenOptions,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use toml::{Table, Value};
+
+use crate::{cmd::StrResult, format::Format};
+
+// layers are merged in order: `extends` base, then each `include`, then the file itself.
+// later layers win; `apps` and `groups` merge by name, `envs` by key, other arrays are replaced.
+// any layer may be toml, json or yaml.
+
+#[inline(always)]
+pub fn is_composed(table: &Table) -> bool {
+    table.contains_key("extends") || table.contains_key("include")
+}
+
+pub fn resolve(path: &str, table: Table) -> Result<Table, String> {
+    compose(Path::new(path), table, &mut Vec::new())
+}
+
+fn compose(path: &Path, mut table: Table, stack: &mut Vec<PathBuf>) -> Result<Table, String> {
+    let canonical = path.canonicalize().str_result()?;
+
+    if stack.contains(&canonical) {
+        let mut chain: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+        chain.push(canonical.display().to_string());
+
+        return Err(format!("Config include cycle: {}", chain.join(" -> ")));
+    }
+
+    stack.push(canonical);
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut merged = Table::new();
+
+    if let Some(base) = table.remove("extends") {
+        match base {
+            Value::String(base) => {
+                let base = dir.join(base);
+                merged = compose(&base, load(&base)?, stack)?;
+            }
+            _ => return Err("extends must be a path!".to_string()),
+        }
+    }
+
+    if let Some(include) = table.remove("include") {
+        match include {
+            Value::Array(include) => {
+                for layer in include {
+                    match layer {
+                        Value::String(layer) => {
+                            let layer = dir.join(layer);
+                            let layer = compose(&layer, load(&layer)?, stack)?;
+
+                            merge(&mut merged, layer);
+                        }
+                        _ => return Err("include must be a list of paths!".to_string()),
+                    }
+                }
+            }
+            _ => return Err("include must be a list of paths!".to_string()),
+        }
+    }
+
+    merge(&mut merged, table);
+
+    stack.pop();
+
+    Ok(merged)
+}
+
+fn load(path: &Path) -> Result<Table, String> {
+    let path = path.to_string_lossy();
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(path.as_ref())
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut source = String::new();
+    file.read_to_string(&mut source).str_result()?;
+
+    Format::detect(&path, &source).parse_table(&path, &source)
+}
+
+fn merge(into: &mut Table, from: Table) {
+    for (key, value) in from {
+        let identity = match key.as_str() {
+            "apps" | "groups" => Some("name"),
+            "envs" => Some("key"),
+            _ => None,
+        };
+
+        match (into.get_mut(&key), value, identity) {
+            (Some(Value::Array(existing)), Value::Array(layer), Some(identity)) => {
+                merge_by(existing, layer, identity);
+            }
+            (Some(Value::Table(existing)), Value::Table(layer), _) => {
+                merge(existing, layer);
+            }
+            (_, value, _) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+fn merge_by(into: &mut Vec<Value>, from: Vec<Value>, identity: &str) {
+    for value in from {
+        let id = value.get(identity).cloned();
+
+        let existing = into
+            .iter_mut()
+            .find(|v| id.is_some() && v.get(identity).eq(&id.as_ref()));
+
+        match (existing, value) {
+            (Some(Value::Table(existing)), Value::Table(layer)) => merge(existing, layer),
+            (_, value) => into.push(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Scratch;
+
+    fn resolved(path: &str) -> Result<Table, String> {
+        resolve(path, load(Path::new(path))?)
+    }
+
+    fn app<'a>(table: &'a Table, name: &str) -> &'a Value {
+        table["apps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["name"].as_str() == Some(name))
+            .unwrap()
+    }
+
+    #[test]
+    fn apps_merge_by_name_and_other_arrays_are_replaced() {
+        let scratch = Scratch::new();
+
+        scratch.write(
+            "base.toml",
+            r#"
+            name = "base"
+            tags = ["a", "b"]
+
+            [[apps]]
+            name = "server"
+            command = "\\server.exe"
+            args = ["--port", "80"]
+
+            [[apps]]
+            name = "client"
+            command = "\\client.exe"
+            "#,
+        );
+        let top = scratch.write(
+            "top.toml",
+            r#"
+            extends = "base.toml"
+            name = "top"
+            tags = ["c"]
+
+            [[apps]]
+            name = "server"
+            args = ["--port", "8080"]
+            "#,
+        );
+
+        let merged = resolved(&top).unwrap();
+
+        assert_eq!(merged["name"].as_str(), Some("top"));
+        assert_eq!(merged["tags"].as_array().unwrap().len(), 1);
+        assert_eq!(merged["apps"].as_array().unwrap().len(), 2);
+
+        let server = app(&merged, "server");
+        assert_eq!(server["command"].as_str(), Some("\\server.exe"));
+        assert_eq!(server["args"][1].as_str(), Some("8080"));
+
+        assert_eq!(
+            app(&merged, "client")["command"].as_str(),
+            Some("\\client.exe")
+        );
+    }
+
+    #[test]
+    fn envs_merge_by_key() {
+        let scratch = Scratch::new();
+
+        scratch.write(
+            "envs.toml",
+            r#"
+            envs = [{ key = "A", value = "1" }, { key = "B", value = "2" }]
+            "#,
+        );
+        let top = scratch.write(
+            "top.toml",
+            r#"
+            include = ["envs.toml"]
+            envs = [{ key = "B", value = "3" }, { key = "C", value = "4" }]
+            "#,
+        );
+
+        let merged = resolved(&top).unwrap();
+        let envs: Vec<(&str, &str)> = merged["envs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["key"].as_str().unwrap(), e["value"].as_str().unwrap()))
+            .collect();
+
+        assert_eq!(envs, vec![("A", "1"), ("B", "3"), ("C", "4")]);
+    }
+
+    #[test]
+    fn base_then_includes_then_the_file_itself() {
+        let scratch = Scratch::new();
+
+        scratch.write("base.toml", "description = \"base\"\nversion = \"1\"\n");
+        scratch.write("one.toml", "description = \"one\"\nhomepage = \"one\"\n");
+        // layers may be written in any format
+        scratch.write("two.json", r#"{ "description": "two" }"#);
+        let top = scratch.write(
+            "top.toml",
+            "extends = \"base.toml\"\ninclude = [\"one.toml\", \"two.json\"]\nname = \"top\"\n",
+        );
+
+        let merged = resolved(&top).unwrap();
+
+        assert_eq!(merged["description"].as_str(), Some("two"));
+        assert_eq!(merged["version"].as_str(), Some("1"));
+        assert_eq!(merged["homepage"].as_str(), Some("one"));
+        assert!(!merged.contains_key("extends"));
+        assert!(!merged.contains_key("include"));
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let scratch = Scratch::new();
+
+        scratch.write("shared/apps.toml", "include = [\"more.toml\"]\n");
+        scratch.write("shared/more.toml", "description = \"nested\"\n");
+        let top = scratch.write("top.toml", "include = [\"shared/apps.toml\"]\n");
+
+        assert_eq!(
+            resolved(&top).unwrap()["description"].as_str(),
+            Some("nested")
+        );
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let scratch = Scratch::new();
+
+        scratch.write("a.toml", "include = [\"b.toml\"]\n");
+        scratch.write("b.toml", "extends = \"a.toml\"\n");
+
+        let error = resolved(&scratch.path("a.toml")).unwrap_err();
+
+        assert!(error.starts_with("Config include cycle:"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_references_are_refused() {
+        let scratch = Scratch::new();
+
+        let extends = scratch.write("extends.toml", "extends = 1\n");
+        let include = scratch.write("include.toml", "include = \"a.toml\"\n");
+
+        assert_eq!(resolved(&extends).unwrap_err(), "extends must be a path!");
+        assert_eq!(
+            resolved(&include).unwrap_err(),
+            "include must be a list of paths!"
+        );
+    }
+}
@@ -0,0 +1,629 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{read_dir, remove_dir_all, remove_file, rename, symlink_metadata, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType, Header};
+
+use crate::{
+    cmd::StrResult,
+    crypto::{self, DecryptReader, EncryptWriter, Secret},
+    manifest::{self, Found, Mode, Report},
+};
+
+// layers follow the oci convention: `.wh.NAME` in a layer deletes NAME from the layers below it
+
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+// what the lower layers leave behind, by path
+#[derive(Serialize, Deserialize)]
+pub struct Lower {
+    pub dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    pub sha256: String,
+}
+
+// the index of the base layers, valid for as long as they keep these checksums
+#[derive(Serialize, Deserialize)]
+struct Index {
+    layers: Vec<String>,
+    entries: HashMap<String, Lower>,
+}
+
+#[inline(always)]
+fn index_path(image_path: &str) -> String {
+    image_path.to_string() + ".lower"
+}
+
+pub fn unpack(reader: impl Read, target: &Path) -> Result<(), String> {
+    let root = target.canonicalize().str_result()?;
+    let mut archive = Archive::new(reader);
+
+    // what this layer put down so far, with its parents; markers only hide what lies below
+    let mut written: HashSet<String> = HashSet::new();
+
+    for entry in archive.entries().str_result()? {
+        let mut entry = entry.str_result()?;
+        let path = manifest::normalize(&entry.path().str_result()?);
+
+        if let Some(dir) = opaque(&path) {
+            clear(&root, &dir, &written)?;
+        } else if let Some(deleted) = whited_out(&path)? {
+            remove(&root, &deleted)?;
+        } else {
+            entry.unpack_in(target).str_result()?;
+
+            let mut parent = path.as_str();
+            while let Some((above, _)) = parent.rsplit_once('/') {
+                written.insert(above.to_string());
+                parent = above;
+            }
+
+            written.insert(path);
+        }
+    }
+
+    Ok(())
+}
+
+// the parent is resolved on disk, so a symlink left by a layer can't lead out of the lab.
+// None when there is nothing there to begin with
+fn resolve(root: &Path, path: &str) -> Result<Option<PathBuf>, String> {
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (root.join(parent), name),
+        None if path.is_empty() => return Ok(Some(root.to_path_buf())),
+        None => (root.to_path_buf(), path),
+    };
+
+    let parent = match parent.canonicalize() {
+        Ok(parent) => parent,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if !parent.starts_with(root) {
+        return Err(format!("Layer reaches outside the lab: {}", path));
+    }
+
+    Ok(Some(parent.join(name)))
+}
+
+// symlinks are removed themselves, never followed
+fn remove(root: &Path, path: &str) -> Result<(), String> {
+    let resolved = match resolve(root, path)? {
+        Some(resolved) => resolved,
+        None => return Ok(()),
+    };
+
+    let result = match symlink_metadata(&resolved) {
+        Ok(metadata) if metadata.is_dir() => remove_dir_all(&resolved),
+        Ok(_) => remove_file(&resolved),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn clear(root: &Path, dir: &str, written: &HashSet<String>) -> Result<(), String> {
+    let resolved = match resolve(root, dir)? {
+        Some(resolved) => resolved,
+        None => return Ok(()),
+    };
+
+    match symlink_metadata(&resolved) {
+        Ok(metadata) if metadata.is_dir() => {}
+        _ => return Ok(()),
+    }
+
+    for item in read_dir(&resolved).str_result()? {
+        let name = item.str_result()?.file_name().to_string_lossy().to_string();
+
+        let path = match dir.is_empty() {
+            true => name,
+            false => format!("{}/{}", dir, name),
+        };
+
+        match written.contains(&path) {
+            true => clear(root, &path, written)?,
+            false => remove(root, &path)?,
+        }
+    }
+
+    Ok(())
+}
+
+pub fn index(readers: Vec<Box<dyn Read>>) -> Result<HashMap<String, Lower>, String> {
+    let mut lower: HashMap<String, Lower> = HashMap::new();
+
+    for reader in readers {
+        let mut archive = Archive::new(reader);
+        let mut written: HashSet<String> = HashSet::new();
+
+        for entry in archive.entries().str_result()? {
+            let mut entry = entry.str_result()?;
+            let path = manifest::normalize(&entry.path().str_result()?);
+
+            if path.is_empty() {
+                continue;
+            }
+
+            if let Some(dir) = opaque(&path) {
+                let prefix = match dir.is_empty() {
+                    true => String::new(),
+                    false => dir + "/",
+                };
+
+                lower.retain(|p, _| written.contains(p) || !p.starts_with(&prefix));
+            } else if let Some(deleted) = whited_out(&path)? {
+                let prefix = deleted.clone() + "/";
+
+                lower.retain(|p, _| !p.eq(&deleted) && !p.starts_with(&prefix));
+            } else {
+                let header = entry.header();
+                let (dir, size, mtime) = (
+                    header.entry_type().is_dir(),
+                    header.size().str_result()?,
+                    header.mtime().str_result()?,
+                );
+
+                let mut hasher = Sha256::new();
+                io::copy(&mut entry, &mut hasher).str_result()?;
+
+                let sha256 = hasher
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+
+                lower.insert(
+                    path.clone(),
+                    Lower {
+                        dir,
+                        size,
+                        mtime,
+                        sha256,
+                    },
+                );
+                written.insert(path);
+            }
+        }
+    }
+
+    Ok(lower)
+}
+
+// reading, and decrypting, every base layer again is most of what a repack costs.
+// the index is kept next to the image, sealed like it, and rebuilt once the layers change
+pub fn cached_index(
+    image_path: &str,
+    layers: Vec<String>,
+    secret: Option<&Secret>,
+    readers: impl FnOnce() -> Result<Vec<Box<dyn Read>>, String>,
+) -> Result<HashMap<String, Lower>, String> {
+    if let Some(cached) = load_index(image_path, secret) {
+        if cached.layers.eq(&layers) {
+            return Ok(cached.entries);
+        }
+    }
+
+    let cached = Index {
+        layers,
+        entries: index(readers()?)?,
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(index_path(image_path))
+        .str_result()?;
+
+    match secret {
+        Some(secret) => {
+            let mut writer = EncryptWriter::new(file, secret)?;
+            serde_json::to_writer(&mut writer, &cached).str_result()?;
+            writer.finish()?.sync_all().str_result()?;
+        }
+        None => {
+            serde_json::to_writer(&mut file, &cached).str_result()?;
+            file.flush().str_result()?;
+            file.sync_all().str_result()?;
+        }
+    }
+
+    Ok(cached.entries)
+}
+
+// anything unreadable is simply built again
+fn load_index(image_path: &str, secret: Option<&Secret>) -> Option<Index> {
+    let path = index_path(image_path);
+    let file = File::open(&path).ok()?;
+
+    match (crypto::detect(&path).ok()?, secret) {
+        (None, None) => serde_json::from_reader(file).ok(),
+        (Some(_), Some(secret)) => {
+            serde_json::from_reader(DecryptReader::new(file, secret).ok()?).ok()
+        }
+        _ => None,
+    }
+}
+
+// the top layer holds whatever differs from the layers below, plus whiteouts for deletions
+pub fn repack(
+    image_path: &str,
+    expanded_path: &str,
+    lower: &HashMap<String, Lower>,
+    secret: Option<&Secret>,
+) -> Result<Report, String> {
+    let found = manifest::walk(expanded_path)?;
+
+    let mut same: Vec<&Found> = Vec::new();
+    let mut changed: Vec<&Found> = Vec::new();
+
+    for f in &found {
+        let unchanged = match lower.get(&f.path) {
+            Some(l) if l.dir || f.dir => l.dir == f.dir,
+            // an edit can keep both the size and the mtime, the content decides
+            Some(l) if l.size == f.size && l.mtime == f.mtime => {
+                manifest::hash(&f.source)?.eq(&l.sha256)
+            }
+            _ => false,
+        };
+
+        match unchanged {
+            true => same.push(f),
+            false => changed.push(f),
+        }
+    }
+
+    let on_disk: HashMap<&str, bool> = found.iter().map(|f| (f.path.as_str(), f.dir)).collect();
+
+    // a folder that became a file, or the other way around, has to go before the new one lands.
+    // only the topmost deleted path needs a whiteout, its children go with it
+    let mut deleted: Vec<&String> = lower
+        .iter()
+        .filter(|(p, l)| on_disk.get(p.as_str()) != Some(&l.dir))
+        .map(|(p, _)| p)
+        .collect();
+    deleted.sort();
+
+    let mut whiteouts: Vec<&String> = Vec::new();
+
+    for path in deleted {
+        if !whiteouts
+            .iter()
+            .any(|w| path.starts_with(&(w.to_string() + "/")))
+        {
+            whiteouts.push(path);
+        }
+    }
+
+    let temporary = image_path.to_string() + ".repack";
+
+    manifest::write_archive(&temporary, secret, |builder| {
+        for path in &whiteouts {
+            let name = match path.rsplit_once('/') {
+                Some((parent, name)) => format!("{}/{}{}", parent, WHITEOUT, name),
+                None => format!("{}{}", WHITEOUT, path),
+            };
+
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(0);
+            header.set_mode(0o644);
+
+            builder
+                .append_data(&mut header, name, io::empty())
+                .str_result()?;
+        }
+
+        for f in &changed {
+            manifest::add(builder, f)?;
+        }
+
+        Ok(())
+    })?;
+
+    rename(&temporary, image_path).str_result()?;
+
+    Ok(Report {
+        mode: Mode::Layer,
+        rewritten: changed.iter().filter(|f| !f.dir).count(),
+        rewritten_bytes: changed.iter().map(|f| f.size).sum(),
+        reused: same.iter().filter(|f| !f.dir).count(),
+        reused_bytes: same.iter().map(|f| f.size).sum(),
+        removed: whiteouts.len(),
+    })
+}
+
+// names that would point at the folder itself or above it are refused
+pub fn whited_out(path: &str) -> Result<Option<String>, String> {
+    if opaque(path).is_some() {
+        return Ok(None);
+    }
+
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, path),
+    };
+
+    let name = match name.strip_prefix(WHITEOUT) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    if name.is_empty() || name.eq(".") || name.eq("..") {
+        return Err(format!("Invalid whiteout: {}", path));
+    }
+
+    match parent {
+        Some(parent) => Ok(Some(format!("{}/{}", parent, name))),
+        None => Ok(Some(name.to_string())),
+    }
+}
+
+// an opaque folder hides everything the layers below put in it, `""` being the root
+pub fn opaque(path: &str) -> Option<String> {
+    match path.rsplit_once('/') {
+        Some((parent, OPAQUE)) => Some(parent.to_string()),
+        None if path.eq(OPAQUE) => Some(String::new()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string, write};
+
+    use tar::Builder;
+
+    use super::*;
+    use crate::testing::Scratch;
+
+    // a path ending in `/` is a folder, `->` makes a symlink, anything else a file with content
+    fn layer(entries: &[&str]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o755);
+
+            if let Some((path, target)) = entry.split_once(" -> ") {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else if entry.ends_with('/') {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                builder
+                    .append_data(&mut header, entry, io::empty())
+                    .unwrap();
+            } else {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(entry.len() as u64);
+                builder
+                    .append_data(&mut header, entry, entry.as_bytes())
+                    .unwrap();
+            }
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn expanded(scratch: &Scratch, layers: &[Vec<u8>]) -> Result<PathBuf, String> {
+        let target = scratch.root.join("lab");
+        create_dir_all(&target).unwrap();
+
+        for layer in layers {
+            unpack(layer.as_slice(), &target)?;
+        }
+
+        Ok(target)
+    }
+
+    // the base is expanded and edited, repacked on top of it and expanded again from scratch
+    fn repacked(scratch: &Scratch, base: Vec<u8>, edit: impl FnOnce(&Path)) -> PathBuf {
+        let lab = expanded(scratch, std::slice::from_ref(&base)).unwrap();
+        edit(&lab);
+
+        let lower = index(vec![Box::new(io::Cursor::new(base.clone()))]).unwrap();
+        let top = scratch.path("top.tar");
+        repack(&top, &lab.to_string_lossy(), &lower, None).unwrap();
+
+        let again = scratch.root.join("again");
+        create_dir_all(&again).unwrap();
+        unpack(base.as_slice(), &again).unwrap();
+        unpack(File::open(&top).unwrap(), &again).unwrap();
+
+        again
+    }
+
+    #[test]
+    fn whiteouts_name_what_they_delete() {
+        assert_eq!(whited_out("a/.wh.b"), Ok(Some("a/b".to_string())));
+        assert_eq!(whited_out(".wh.b"), Ok(Some("b".to_string())));
+        assert_eq!(whited_out("a/b"), Ok(None));
+        assert_eq!(whited_out("a/.wh..wh..opq"), Ok(None));
+        assert_eq!(opaque("a/.wh..wh..opq"), Some("a".to_string()));
+        assert_eq!(opaque(".wh..wh..opq"), Some(String::new()));
+    }
+
+    #[test]
+    fn whiteouts_out_of_the_folder_are_refused() {
+        assert!(whited_out(".wh...").is_err());
+        assert!(whited_out("a/.wh..").is_err());
+        assert!(whited_out("a/.wh.").is_err());
+    }
+
+    #[test]
+    fn whiteouts_delete_from_lower_layers() {
+        let scratch = Scratch::new();
+        let target = expanded(
+            &scratch,
+            &[
+                layer(&["a/", "a/x", "a/y", "b/", "b/z"]),
+                layer(&["a/.wh.x", ".wh.b"]),
+            ],
+        )
+        .unwrap();
+
+        assert!(!target.join("a/x").exists());
+        assert!(target.join("a/y").exists());
+        assert!(!target.join("b").exists());
+    }
+
+    #[test]
+    fn opaque_folders_keep_only_their_own_layer() {
+        let scratch = Scratch::new();
+        let target = expanded(
+            &scratch,
+            &[
+                layer(&["a/", "a/x", "a/sub/", "a/sub/y", "c"]),
+                layer(&["a/", "a/sub/", "a/sub/new", "a/.wh..wh..opq"]),
+            ],
+        )
+        .unwrap();
+
+        assert!(!target.join("a/x").exists());
+        assert!(!target.join("a/sub/y").exists());
+        assert_eq!(
+            read_to_string(target.join("a/sub/new")).unwrap(),
+            "a/sub/new"
+        );
+        assert!(target.join("c").exists());
+    }
+
+    #[test]
+    fn whiteouts_through_symlinks_are_refused() {
+        let scratch = Scratch::new();
+        let outside = scratch.write("outside/victim", "keep me");
+        let outside_dir = scratch.path("outside");
+
+        let result = expanded(
+            &scratch,
+            &[
+                layer(&[&format!("link -> {}", outside_dir)]),
+                layer(&["link/.wh.victim"]),
+            ],
+        );
+
+        assert!(result.is_err());
+        assert!(Path::new(&outside).exists());
+    }
+
+    #[test]
+    fn whited_out_symlinks_are_removed_not_followed() {
+        let scratch = Scratch::new();
+        let outside = scratch.write("outside/victim", "keep me");
+        let outside_dir = scratch.path("outside");
+
+        let target = expanded(
+            &scratch,
+            &[
+                layer(&[&format!("link -> {}", outside_dir)]),
+                layer(&[".wh.link"]),
+            ],
+        )
+        .unwrap();
+
+        assert!(symlink_metadata(target.join("link")).is_err());
+        assert!(Path::new(&outside).exists());
+    }
+
+    #[test]
+    fn index_follows_whiteouts_and_opaque_folders() {
+        let readers: Vec<Box<dyn Read>> = vec![
+            Box::new(io::Cursor::new(layer(&["a/", "a/x", "b/", "b/y", "c"]))),
+            Box::new(io::Cursor::new(layer(&[
+                ".wh.c",
+                "a/new",
+                "a/.wh..wh..opq",
+            ]))),
+        ];
+
+        let lower = index(readers).unwrap();
+        let mut paths: Vec<&str> = lower.keys().map(|p| p.as_str()).collect();
+        paths.sort();
+
+        assert_eq!(paths, ["a", "a/new", "b", "b/y"]);
+    }
+
+    #[test]
+    fn index_is_cached_until_the_layers_change() {
+        let scratch = Scratch::new();
+        let image = scratch.path("lab.tar");
+
+        let readers = || -> Result<Vec<Box<dyn Read>>, String> {
+            Ok(vec![Box::new(io::Cursor::new(layer(&["a"])))])
+        };
+        let unread = || -> Result<Vec<Box<dyn Read>>, String> { Err("read again".to_string()) };
+
+        let layers = vec!["1111".to_string()];
+
+        assert!(cached_index(&image, layers.clone(), None, readers)
+            .unwrap()
+            .contains_key("a"));
+        assert!(cached_index(&image, layers, None, unread)
+            .unwrap()
+            .contains_key("a"));
+        assert!(cached_index(&image, vec!["2222".to_string()], None, unread).is_err());
+    }
+
+    #[test]
+    fn cached_index_is_sealed_with_the_image() {
+        let scratch = Scratch::new();
+        let image = scratch.path("lab.tar");
+        let secret = Secret::KeyFile(scratch.write("key", "key"));
+
+        let readers = || -> Result<Vec<Box<dyn Read>>, String> {
+            Ok(vec![Box::new(io::Cursor::new(layer(&["secret-name"])))])
+        };
+
+        cached_index(&image, vec![], Some(&secret), readers).unwrap();
+
+        assert!(crypto::detect(&index_path(&image)).unwrap().is_some());
+    }
+
+    #[test]
+    fn folders_and_files_can_trade_places() {
+        let scratch = Scratch::new();
+        let again = repacked(&scratch, layer(&["a/", "a/x", "b"]), |lab| {
+            remove_dir_all(lab.join("a")).unwrap();
+            write(lab.join("a"), "file").unwrap();
+
+            remove_file(lab.join("b")).unwrap();
+            create_dir_all(lab.join("b")).unwrap();
+            write(lab.join("b/y"), "y").unwrap();
+        });
+
+        assert_eq!(read_to_string(again.join("a")).unwrap(), "file");
+        assert_eq!(read_to_string(again.join("b/y")).unwrap(), "y");
+    }
+
+    #[test]
+    fn edits_keeping_size_and_mtime_are_kept() {
+        let scratch = Scratch::new();
+        let again = repacked(&scratch, layer(&["a"]), |lab| {
+            let modified = lab.join("a").metadata().unwrap().modified().unwrap();
+
+            write(lab.join("a"), "b").unwrap();
+            File::options()
+                .write(true)
+                .open(lab.join("a"))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        });
+
+        assert_eq!(read_to_string(again.join("a")).unwrap(), "b");
+    }
+}
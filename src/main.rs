@@ -1,10 +1,25 @@
+mod bundle;
 mod cmd;
+mod crypto;
+mod format;
 mod image;
+mod include;
+mod layer;
 mod manager;
+mod manifest;
+mod oci;
+mod preflight;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod signature;
+mod store;
+#[cfg(test)]
+mod testing;
+mod validate;
 
 use std::env::args;
 
-use cmd::{parse_args, usage_and_exit, RunOptions::*};
+use cmd::{parse_args, usage_and_exit, RunOptions::*, Source};
 use manager::manage;
 
 fn main() -> Result<(), String> {
@@ -15,32 +30,66 @@ fn main() -> Result<(), String> {
 
     match run_options {
         Exit => {}
-        Import(config, image) => {
+        Import(config, image, layers, store, Some(Source::Bundle(bundle))) => {
+            if !layers.is_empty() { usage_and_exit!(); }
+
+            manage::import_bundle(bundle, config, image, store)?;
+        }
+        Import(None, _, _, _, _) => {
+            usage_and_exit!();
+        }
+        Import(Some(config), image, layers, store, Some(Source::Dir(dir))) => {
+            manage::import_dir(dir, config, image, layers, store)?;
+        }
+        Import(Some(config), image, layers, store, Some(Source::Oci(tarball))) => {
+            if !layers.is_empty() { usage_and_exit!(); }
+
+            manage::import_oci(
+                tarball,
+                config,
+                match image {
+                    Some(image) => image,
+                    None => { usage_and_exit!(); }
+                },
+                store
+            )?;
+        }
+        Import(Some(config), image, layers, store, None) => {
             manage::import_lab(
                 match image {
                     Some(image) => image,
                     None => { usage_and_exit!(); }
                 },
-                config
+                config,
+                layers,
+                store
             )?;
         }
-        List => {
-            manage::list()?;
+        Validate(config) => {
+            manage::validate(config)?;
+        }
+        Check(name) => {
+            manage::check(name)?;
+        }
+        List(tag) => {
+            manage::list(tag)?;
         }
         ListApps(name) => {
             manage::list_apps(name)?;
         }
-        Run(name, app, drive_letter, arg_vector) => {
+        Run(name, app, drive_letter, arg_vector, params, profile) => {
             manage::run(
                 name,
-                match app {
-                    Some(app) => app,
-                    None => { usage_and_exit!(); }
-                },
+                app,
                 drive_letter,
-                arg_vector
+                arg_vector,
+                params,
+                profile
             )?;
         }
+        RunGroup(name, group, params, profile) => {
+            manage::run_group(name, group, params, profile)?;
+        }
         Change(name, image) => {
             manage::change(
                 name,
@@ -59,23 +108,61 @@ fn main() -> Result<(), String> {
                 }
             )?;
         }
-        Expand(name, path) => {
+        ExportConfig(name, path) => {
+            manage::export_config(name, path)?;
+        }
+        Export(name, file) => {
+            manage::export(name, file)?;
+        }
+        Expand(name, path, verify, key_file) => {
             manage::expand(
                 name,
                 match path {
                     Some(path) => path,
                     None => { usage_and_exit!(); }
-                }
+                },
+                verify,
+                key_file
             )?;
         }
         Discard(name) => {
             manage::discard(name)?;
         }
-        Repack(name) => {
-            manage::repack(name)?;
+        Repack(name, encrypt, key_file) => {
+            manage::repack(name, encrypt, key_file)?;
+        }
+        Restore(name, verify, key_file) => {
+            manage::restore(name, verify, key_file)?;
+        }
+        Snapshot(name, snapshot, key_file) => {
+            manage::snapshot(name, snapshot, key_file)?;
+        }
+        Snapshots(name) => {
+            manage::snapshots(name)?;
+        }
+        Rollback(name, snapshot, key_file) => {
+            manage::rollback(name, snapshot, key_file)?;
+        }
+        RemoveSnapshot(name, snapshot) => {
+            manage::remove_snapshot(name, snapshot)?;
+        }
+        Verify(name) => {
+            manage::verify(name)?;
+        }
+        Sign(name, key) => {
+            manage::sign(
+                name,
+                match key {
+                    Some(key) => key,
+                    None => { usage_and_exit!(); }
+                }
+            )?;
+        }
+        GenKey(key) => {
+            manage::gen_key(key)?;
         }
-        Restore(name) => {
-            manage::restore(name)?;
+        Gc => {
+            manage::gc()?;
         }
         Remove(name) => {
             manage::remove(name)?;
@@ -92,6 +179,21 @@ fn main() -> Result<(), String> {
         Unmount(name) => {
             manage::unmount(name)?;
         }
+        AppAdd(name, app, changes) => {
+            manage::app_add(name, app, changes)?;
+        }
+        AppEdit(name, app, changes) => {
+            manage::app_edit(name, app, changes)?;
+        }
+        AppRemove(name, app) => {
+            manage::app_remove(name, app)?;
+        }
+        EnvSet(name, app, key, value) => {
+            manage::env_set(name, app, key, value)?;
+        }
+        EnvUnset(name, app, key) => {
+            manage::env_unset(name, app, key)?;
+        }
     };
 
     Ok(())
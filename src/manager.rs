@@ -149,52 +149,439 @@ mod cache {
     }
 }
 
+mod settings {
+    use std::{fs::read_to_string, io::ErrorKind};
+
+    use serde::Deserialize;
+
+    use crate::{format::Format, signature::Signing};
+
+    // the host's own settings, kept next to the cache; nothing a lab brings along can change them
+    #[derive(Deserialize, Default)]
+    #[serde(deny_unknown_fields)]
+    pub struct Settings {
+        #[serde(default)]
+        pub signing: Signing,
+    }
+
+    impl Settings {
+        pub fn load(path: String) -> Result<Self, String> {
+            let source = match read_to_string(&path) {
+                Ok(source) => source,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+                Err(e) => return Err(format!("{}: {}", path, e)),
+            };
+
+            let settings: Self = Format::Toml.parse(&path, &source)?;
+
+            settings
+                .signing
+                .check()
+                .map_err(|e| format!("{}: {}", path, e))?;
+
+            Ok(settings)
+        }
+    }
+}
+
 pub mod manage {
-    use std::io::{stdout, Write};
+    use std::{
+        env,
+        fs::{remove_file, File},
+        io::{stdout, Write},
+        path::Path,
+        process::Child,
+        time::{SystemTime, UNIX_EPOCH},
+    };
 
     use colored::Colorize;
+    use tar::Builder;
+
+    use crate::{
+        bundle::Bundle,
+        cmd::{AppChanges, StrResult},
+        crypto::{KeyKind, Secret},
+        image::{Invocation, Lab, LabConfig, ParamKind},
+        oci, signature,
+        store::Store,
+        validate,
+    };
 
-    use crate::{cmd::StrResult, image::Lab};
-
-    use super::cache::Cache;
+    use super::{cache::Cache, settings::Settings};
 
     const CACHE_PATH: &str = ".laboratory\\Cache.toml";
+    const SETTINGS_PATH: &str = ".laboratory\\Config.toml";
+    const PASSPHRASE_ENV: &str = "LABORATORY_PASSPHRASE";
+
+    pub fn import_lab(
+        image: String,
+        config: String,
+        layers: Vec<String>,
+        store: bool,
+    ) -> Result<(), String> {
+        let mut lab = Lab::from_image(image.clone());
+
+        lab.read_config(&config)?;
+
+        for layer in layers {
+            lab.add_layer(layer)?;
+        }
+
+        let settings = Settings::load(settings_path())?;
+        let mut cache = Cache::load(cache_path())?;
+
+        if cache.search(&lab.config.name).is_ok() {
+            return Err("Lab with similar name exists!".to_string());
+        }
+
+        // a lab on top of shared layers may start with nothing of its own
+        let created = !lab.layers.is_empty() && !Path::new(&image).exists();
 
-    pub fn import_lab(image: String, config: String) -> Result<(), String> {
-        let mut lab = Lab::from_image(image);
+        if created {
+            // an image built here has nobody's signature on it yet
+            if settings.signing.required {
+                return Err("Signed images are required, the top image is missing!".to_string());
+            }
+
+            Builder::new(File::create(&image).str_result()?)
+                .into_inner()
+                .str_result()?
+                .sync_all()
+                .str_result()?;
+        }
+
+        // an image created above goes again unless the lab makes it into the cache
+        let result = (|| {
+            lab.record_image()?;
+            lab.verify_signature(&settings.signing)?;
+
+            // signatures are checked on the image, before it is split into the store
+            if store {
+                lab.store()?;
+            }
+
+            warn(&lab.config);
+
+            cache.add(lab)
+        })();
+
+        if result.is_err() && created {
+            remove_file(&image).ok();
+        }
+
+        result?;
+        cache.write()?;
+
+        Ok(())
+    }
+
+    // the folder stays where it is and becomes the expanded lab
+    pub fn import_dir(
+        dir: String,
+        config: String,
+        image: Option<String>,
+        layers: Vec<String>,
+        store: bool,
+    ) -> Result<(), String> {
+        // an image built here has nobody's signature on it yet
+        if Settings::load(settings_path())?.signing.required {
+            return Err("Signed images are required, a folder can't be imported!".to_string());
+        }
+
+        // the folder is expanded in place, without an image or a tree it would be the only copy
+        if image.is_none() && !store {
+            return Err("Folder imports need an image or the store!".to_string());
+        }
+
+        let mut lab = Lab::from_expanded(dir);
+        lab.image_path = image;
 
         lab.read_config(&config)?;
 
         let mut cache = Cache::load(cache_path())?;
 
+        // nothing gets built for a name that's taken
+        if cache.search(&lab.config.name).is_ok() {
+            return Err("Lab with similar name exists!".to_string());
+        }
+
+        for layer in layers {
+            lab.add_layer(layer)?;
+        }
+
+        lab.adopt(store)?;
+
+        warn(&lab.config);
+
         cache.add(lab)?;
         cache.write()?;
 
         Ok(())
     }
 
-    pub fn list() -> Result<(), String> {
+    // the config is derived from the container image unless one is already there
+    pub fn import_oci(
+        tarball: String,
+        config: String,
+        image: String,
+        store: bool,
+    ) -> Result<(), String> {
+        if Settings::load(settings_path())?.signing.required {
+            return Err("Signed images are required, a container can't be imported!".to_string());
+        }
+
+        if Path::new(&image).exists() {
+            return Err("Image already exists!".to_string());
+        }
+
+        let mut lab = Lab::from_image(image.clone());
+
+        // an existing config is checked before the container is flattened
+        let existing = Path::new(&config).exists();
+
+        if existing {
+            lab.read_config(&config)?;
+        }
+
+        let mut cache = Cache::load(cache_path())?;
+
+        if existing && cache.search(&lab.config.name).is_ok() {
+            return Err("Lab with similar name exists!".to_string());
+        }
+
+        let starter = oci::import(&tarball, &image)?;
+
+        // the flattened image goes again unless the lab makes it into the cache
+        let result = (|| {
+            if !existing {
+                validate::check_config(&starter).map_err(|problems| problems.join("\n"))?;
+
+                if cache.search(&starter.name).is_ok() {
+                    return Err("Lab with similar name exists!".to_string());
+                }
+
+                lab.config = starter;
+                lab.write_config(&config)?;
+
+                println!(
+                    "{} {}",
+                    "Starter config written to".green().bold(),
+                    config.cyan()
+                );
+            }
+
+            lab.record_image()?;
+
+            if store {
+                lab.store()?;
+            }
+
+            warn(&lab.config);
+
+            cache.add(lab)
+        })();
+
+        if result.is_err() {
+            remove_file(&image).ok();
+        }
+
+        result?;
+        cache.write()?;
+
+        Ok(())
+    }
+
+    // the image is unpacked next to the bundle unless told otherwise, named after the lab
+    pub fn import_bundle(
+        path: String,
+        config: Option<String>,
+        image: Option<String>,
+        store: bool,
+    ) -> Result<(), String> {
+        let settings = Settings::load(settings_path())?;
+        let bundle = Bundle::open(&path)?;
+
+        let config = match config {
+            Some(config) => {
+                validate::check_file(&config).map_err(|problems| problems.join("\n"))?
+            }
+            None => bundle.config()?,
+        };
+
+        // the image is named after the validated config, never after what the metadata claims
+        let image = match image {
+            Some(image) => image,
+            None if config.name.contains(['/', '\\', ':']) || config.name.contains("..") => {
+                return Err("Lab name can't be used as a file name, choose an image!".to_string());
+            }
+            None => Path::new(&path)
+                .with_file_name(format!("{}.tar", config.name))
+                .to_string_lossy()
+                .to_string(),
+        };
+
+        let mut lab = Lab::from_image(image.clone());
+        lab.config = config;
+
+        let mut cache = Cache::load(cache_path())?;
+
+        // checked before anything is unpacked
+        if cache.search(&lab.config.name).is_ok() {
+            return Err("Lab with similar name exists!".to_string());
+        }
+
+        let layers = bundle.unpack(&image)?;
+        let name = lab.config.name.clone();
+
+        // the unpacked files go again unless the lab makes it into the cache
+        let result = (|| {
+            for layer in &layers {
+                lab.add_layer(layer.clone())?;
+            }
+
+            lab.record_image()?;
+            lab.verify_signature(&settings.signing)?;
+
+            if store {
+                lab.store()?;
+            }
+
+            warn(&lab.config);
+
+            cache.add(lab)
+        })();
+
+        if result.is_err() {
+            for unpacked in layers.iter().chain([&image]) {
+                remove_file(unpacked).ok();
+                remove_file(signature::signature_path(unpacked)).ok();
+            }
+        }
+
+        result?;
+        cache.write()?;
+
+        println!(
+            "{} {} {} {}",
+            "Imported".green().bold(),
+            name.cyan().bold(),
+            "from".green(),
+            path.cyan()
+        );
+
+        Ok(())
+    }
+
+    pub fn validate(config: String) -> Result<(), String> {
+        match validate::check_file(&config) {
+            Ok(config) => {
+                warn(&config);
+
+                println!("{}", "Config is valid!".green().bold());
+
+                Ok(())
+            }
+            Err(problems) => {
+                for problem in &problems {
+                    println!("{}", problem.red());
+                }
+
+                Err(format!("{} problem(s) found!", problems.len()))
+            }
+        }
+    }
+
+    pub fn check(name: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        let path = lab.expanded_path.clone().unwrap_or(".".to_string());
+
+        match lab.preflight(&path) {
+            Ok(_) => {
+                println!("{}", "All requirements met!".green().bold());
+
+                Ok(())
+            }
+            Err(missing) => {
+                for problem in &missing {
+                    println!("{}", problem.red());
+                }
+
+                Err(format!("{} requirement(s) missing!", missing.len()))
+            }
+        }
+    }
+
+    pub fn list(tag: Option<String>) -> Result<(), String> {
         let cache = Cache::load(cache_path())?;
 
-        print!("\n");
+        println!();
 
         for lab in cache {
-            print!(
-                "{} = {}\n",
+            if let Some(tag) = &tag {
+                if !lab.config.tags.contains(tag) {
+                    continue;
+                }
+            }
+
+            println!(
+                "{} = {}",
                 "name".green().bold(),
                 lab.config.name.cyan().bold()
             );
-            print!("{}\n", "--------------------------".blue().bold());
+            println!("{}", "--------------------------".blue().bold());
+
+            if let Some(description) = &lab.config.description {
+                println!("{}", description.cyan());
+            }
+
+            if let Some(version) = &lab.config.version {
+                println!("{} -> {}", "version".green(), version.cyan());
+            }
+
+            if !lab.config.authors.is_empty() {
+                println!(
+                    "{} -> {}",
+                    "authors".green(),
+                    lab.config.authors.join(", ").cyan()
+                );
+            }
+
+            if !lab.config.tags.is_empty() {
+                println!(
+                    "{} -> {}",
+                    "tags".green(),
+                    lab.config.tags.join(", ").cyan()
+                );
+            }
+
+            if let Some(homepage) = &lab.config.homepage {
+                println!("{} -> {}", "homepage".green(), homepage.cyan());
+            }
+
+            for layer in &lab.layers {
+                println!("{} -> {}", "layer".green(), layer.path.cyan());
+            }
 
             if let Some(image_path) = &lab.image_path {
-                print!("{} -> {}\n", "image".green(), image_path.cyan());
+                println!("{} -> {}", "image".green(), image_path.cyan());
+            }
+
+            if let Some(image_sha256) = &lab.image_sha256 {
+                println!("{} -> {}", "sha256".green(), image_sha256.cyan());
+            }
+
+            if let Some(tree) = &lab.tree {
+                println!("{} -> {}", "stored".green(), tree.cyan());
             }
 
             if let Some(expanded_path) = &lab.expanded_path {
-                print!("{} -> {}\n", "expanded".green(), expanded_path.cyan());
+                println!("{} -> {}", "expanded".green(), expanded_path.cyan());
 
                 if let Some(drive_letter) = &lab.drive_letter {
-                    print!("{} -> {}:\\\n", "mounted".green(), drive_letter.cyan());
+                    println!("{} -> {}:\\", "mounted".green(), drive_letter.cyan());
                 }
             }
 
@@ -211,25 +598,184 @@ pub mod manage {
 
         let lab = cache.search(&name)?;
 
-        print!("\n");
+        println!();
+
+        if !lab.config.vars.is_empty() {
+            println!("{}", "vars:".green().bold());
+            for (key, value) in &lab.config.vars {
+                println!("\t{} = {}", key.cyan(), value.cyan());
+            }
+            println!();
+        }
+
+        if !lab.config.envs.is_empty() {
+            println!("{}", "env:".green().bold());
+            for env in &lab.config.envs {
+                println!("\t{} = {}", env.key.cyan(), env.value.cyan());
+            }
+            println!();
+        }
+
+        if let Some(default_app) = &lab.config.default_app {
+            print!(
+                "{} -> {}\n\n",
+                "default".green().bold(),
+                default_app.cyan().bold()
+            );
+        }
 
         for app in &lab.config.apps {
-            print!("{} = {}\n", "name".green().bold(), app.name.cyan().bold());
-            print!("{}\n", "--------------------------".blue().bold());
+            println!("{} = {}", "name".green().bold(), app.name.cyan().bold());
+            println!("{}", "--------------------------".blue().bold());
+
+            if !app.aliases.is_empty() {
+                println!("{} -> {}", "aliases".green(), app.aliases.join(", ").cyan());
+            }
 
-            print!("{} -> {}\n", "command".green(), app.command.cyan());
+            let available = match app.is_available() {
+                true => "yes".green(),
+                false => "no".red(),
+            };
+            println!("{} -> {}", "available".green(), available);
 
-            print!("{}\n", "args:".green());
+            println!("{} -> {}", "command".green(), app.command.cyan());
+
+            for (os, target) in &app.target {
+                println!("{} {}:", "target".green(), os.cyan().bold());
+
+                if let Some(command) = &target.command {
+                    println!("\t{} -> {}", "command".green(), command.cyan());
+                }
+
+                if let Some(args) = &target.args {
+                    println!("\t{} -> {}", "args".green(), args.join(" ").cyan());
+                }
+
+                if let Some(work_dir) = &target.work_dir {
+                    println!("\t{} -> {}", "workdir".green(), work_dir.cyan());
+                }
+
+                for env in &target.envs {
+                    println!("\t{} = {}", env.key.cyan(), env.value.cyan());
+                }
+            }
+
+            println!("{}", "args:".green());
             for arg in &app.args {
-                print!("\t{}\n", arg.cyan());
+                println!("\t{}", arg.cyan());
             }
 
-            print!("{} -> {}\n", "workdir".green(), app.work_dir.cyan());
+            println!("{} -> {}", "workdir".green(), app.work_dir.cyan());
+
+            println!("{}", "params:".green());
+            for param in &app.params {
+                let kind = match param.kind {
+                    ParamKind::Enum => format!("enum({})", param.values.join("|")),
+                    kind => kind.as_str().to_string(),
+                };
+
+                let mut line = format!("{}: {}", param.name, kind);
+
+                if param.required {
+                    line += " (required)";
+                }
+
+                if let Some(default) = &param.default {
+                    line += &format!(" (default = {})", default);
+                }
+
+                if let Some(help) = &param.help {
+                    line += &format!(" - {}", help);
+                }
+
+                println!("\t{}", line.cyan());
+            }
 
-            print!("{}\n", "env:".green());
+            println!("{}", "env:".green());
 
             for env in &app.envs {
-                print!("\t{} = {}\n", env.key.cyan(), env.value.cyan());
+                println!("\t{} = {}", env.key.cyan(), env.value.cyan());
+            }
+
+            println!("{}", "pre_run:".green());
+            for hook in &app.pre_run {
+                println!("\t{}", hook.cyan());
+            }
+
+            println!("{}", "post_run:".green());
+            for hook in &app.post_run {
+                println!("\t{}", hook.cyan());
+            }
+
+            if let Some(sandbox) = &app.sandbox {
+                let access = match sandbox.read_only {
+                    true => "read-only",
+                    false => "read-write",
+                };
+                let network = match sandbox.network {
+                    true => "network",
+                    false => "no network",
+                };
+
+                println!(
+                    "{} -> {} ({}, {})",
+                    "sandbox".green(),
+                    sandbox.mount_point.cyan(),
+                    access.cyan(),
+                    network.cyan()
+                );
+            }
+
+            print!("\n\n");
+        }
+
+        for (name, profile) in &lab.config.profiles {
+            let title = match lab.config.default_profile.as_ref() == Some(name) {
+                true => format!("{} (default)", name),
+                false => name.clone(),
+            };
+
+            println!("{} = {}", "profile".green().bold(), title.cyan().bold());
+            println!("{}", "--------------------------".blue().bold());
+
+            println!("{}", "env:".green());
+            for env in &profile.envs {
+                println!("\t{} = {}", env.key.cyan(), env.value.cyan());
+            }
+
+            for (app, overlay) in &profile.apps {
+                println!("{} {}:", "app".green(), app.cyan().bold());
+
+                if let Some(args) = &overlay.args {
+                    println!("\t{} -> {}", "args".green(), args.join(" ").cyan());
+                }
+
+                for env in &overlay.envs {
+                    println!("\t{} = {}", env.key.cyan(), env.value.cyan());
+                }
+            }
+
+            print!("\n\n");
+        }
+
+        for group in &lab.config.groups {
+            println!("{} = {}", "group".green().bold(), group.name.cyan().bold());
+            println!("{}", "--------------------------".blue().bold());
+
+            if let Some(main) = &group.main {
+                println!("{} -> {}", "main".green(), main.cyan());
+            }
+
+            println!("{}", "members:".green());
+            for member in &group.members {
+                match member.depends_on.is_empty() {
+                    true => println!("\t{}", member.app.cyan()),
+                    false => println!(
+                        "\t{} -> {}",
+                        member.app.cyan(),
+                        member.depends_on.join(", ").cyan()
+                    ),
+                }
             }
 
             print!("\n\n");
@@ -242,9 +788,11 @@ pub mod manage {
 
     pub fn run(
         name: String,
-        app: String,
+        app: Option<String>,
         drive_letter: Option<String>,
         arg_vector: Option<Vec<String>>,
+        params: Vec<(String, String)>,
+        profile: Option<String>,
     ) -> Result<(), String> {
         let mut cache = Cache::load(cache_path())?;
 
@@ -261,13 +809,145 @@ pub mod manage {
         // repetition is not ideal
         let lab = cache.search(&name)?;
 
-        let mut child = lab.run(&app, arg_vector)?;
-        child.wait().str_result()?;
+        let app = match app {
+            Some(app) => app,
+            None => lab.default_app()?,
+        };
 
-        Ok(())
+        let path = lab.expanded_path.clone().unwrap_or(".".to_string());
+        lab.preflight(&path).map_err(|missing| missing.join("\n"))?;
+
+        let invocation = lab.prepare(&app, params, profile)?;
+
+        lab.pre_run(&app, &invocation)?;
+
+        let result = lab
+            .run(&app, arg_vector, &invocation)
+            .and_then(|mut child| child.wait().str_result());
+
+        // post-run hooks undo what pre-run ones set up, even when the app failed to start
+        let post_run = lab.post_run(&app, &invocation);
+
+        result.and(post_run)
     }
 
-    pub fn expand(name: String, path: String) -> Result<(), String> {
+    // each parameter goes to every member that declares it
+    pub fn run_group(
+        name: String,
+        group: String,
+        params: Vec<(String, String)>,
+        profile: Option<String>,
+    ) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        let path = lab.expanded_path.clone().unwrap_or(".".to_string());
+        lab.preflight(&path).map_err(|missing| missing.join("\n"))?;
+
+        let (members, main) = lab.group_order(&group)?;
+
+        for (key, _) in &params {
+            let mut declared = false;
+
+            for member in &members {
+                declared |= lab.declares(&member.app, key)?;
+            }
+
+            if !declared {
+                return Err(format!("Unknown parameter: {}", key));
+            }
+        }
+
+        let mut resolved = Vec::new();
+        for member in members {
+            let mut given = Vec::new();
+
+            for (key, value) in &params {
+                if lab.declares(&member.app, key)? {
+                    given.push((key.clone(), value.clone()));
+                }
+            }
+
+            resolved.push((member, lab.prepare(&member.app, given, profile.clone())?));
+        }
+
+        let mut started: Vec<(&str, Invocation, Child)> = Vec::new();
+
+        for (member, invocation) in resolved {
+            let result = lab.pre_run(&member.app, &invocation).and_then(|_| {
+                let started = lab
+                    .run(&member.app, None, &invocation)
+                    .and_then(|mut child| {
+                        if let Some(ready) = &member.ready {
+                            if let Err(e) =
+                                lab.wait_ready(&member.app, ready, &mut child, &invocation)
+                            {
+                                child.kill().ok();
+                                child.wait().ok();
+
+                                return Err(e);
+                            }
+                        }
+
+                        Ok(child)
+                    });
+
+                // a member that never came up still gets its post-run hooks
+                if started.is_err() {
+                    if let Err(e) = lab.post_run(&member.app, &invocation) {
+                        println!("{}", e.red());
+                    }
+                }
+
+                started
+            });
+
+            match result {
+                Ok(child) => started.push((&member.app, invocation, child)),
+                Err(e) => {
+                    shutdown(lab, started);
+
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = match started.iter().position(|(app, _, _)| main.eq(app)) {
+            Some(index) => {
+                let (app, invocation, mut child) = started.remove(index);
+
+                let waited = child.wait().str_result();
+                let post_run = lab.post_run(app, &invocation);
+
+                waited.and(post_run)
+            }
+            None => Ok(()),
+        };
+
+        // the dependencies are stopped whatever happened to the main app
+        shutdown(lab, started);
+
+        result
+    }
+
+    fn shutdown(lab: &Lab, started: Vec<(&str, Invocation, Child)>) {
+        for (app, invocation, mut child) in started.into_iter().rev() {
+            child.kill().ok();
+            child.wait().ok();
+
+            if let Err(e) = lab.post_run(app, &invocation) {
+                println!("{}", e.red());
+            }
+        }
+    }
+
+    pub fn expand(
+        name: String,
+        path: String,
+        verify: bool,
+        key_file: Option<String>,
+    ) -> Result<(), String> {
         let mut cache = Cache::load(cache_path())?;
 
         let lab = cache.search(&name)?;
@@ -276,7 +956,17 @@ pub mod manage {
             return Err("Lab is mounted!".to_string());
         }
 
-        lab.expand(path)?;
+        lab.preflight(&path).map_err(|missing| missing.join("\n"))?;
+
+        if verify {
+            lab.verify_image()?;
+        }
+
+        lab.verify_signature(&Settings::load(settings_path())?.signing)?;
+
+        let secret = unlock(lab.encryption()?, key_file)?;
+
+        lab.expand(path, secret.as_ref())?;
 
         cache.write()?;
 
@@ -299,7 +989,7 @@ pub mod manage {
         Ok(())
     }
 
-    pub fn repack(name: String) -> Result<(), String> {
+    pub fn repack(name: String, encrypt: bool, key_file: Option<String>) -> Result<(), String> {
         let mut cache = Cache::load(cache_path())?;
 
         let lab = cache.search(&name)?;
@@ -308,23 +998,248 @@ pub mod manage {
             return Err("Lab is mounted!".to_string());
         }
 
-        lab.repack()?;
+        let secret = match (key_file, encrypt) {
+            (Some(key_file), _) => Some(Secret::KeyFile(key_file)),
+            (None, true) => Some(Secret::Passphrase(passphrase(true)?)),
+            // an encrypted image stays encrypted, under the secret it already has
+            (None, false) => {
+                let secret = unlock(lab.encryption()?, None)?;
+
+                if let Some(secret) = &secret {
+                    lab.check_secret(secret)?;
+                }
+
+                secret
+            }
+        };
+
+        let report = lab.repack(secret.as_ref())?;
 
         cache.write()?;
 
+        println!(
+            "{} {} ({})",
+            "Repacked".green().bold(),
+            name.cyan().bold(),
+            report.mode.as_str().cyan()
+        );
+        println!(
+            "{} {} file(s), {}",
+            "rewritten".green(),
+            report.rewritten.to_string().cyan(),
+            bytes(report.rewritten_bytes).cyan()
+        );
+        println!(
+            "{} {} file(s), {}",
+            "reused".green(),
+            report.reused.to_string().cyan(),
+            bytes(report.reused_bytes).cyan()
+        );
+        println!(
+            "{} {} file(s)",
+            "removed".green(),
+            report.removed.to_string().cyan()
+        );
+
         Ok(())
     }
 
-    pub fn restore(name: String) -> Result<(), String> {
+    pub fn restore(name: String, verify: bool, key_file: Option<String>) -> Result<(), String> {
         let mut cache = Cache::load(cache_path())?;
 
         let lab = cache.search(&name)?;
 
-        if let Some(_) = &lab.drive_letter {
+        if lab.drive_letter.is_some() {
             return Err("Lab is mounted!".to_string());
         }
 
-        lab.restore()?;
+        if verify {
+            lab.verify_image()?;
+        }
+
+        let secret = unlock(lab.encryption()?, key_file)?;
+
+        lab.restore(secret.as_ref())?;
+
+        Ok(())
+    }
+
+    pub fn snapshot(
+        name: String,
+        snapshot: Option<String>,
+        key_file: Option<String>,
+    ) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .str_result()?
+                .as_secs()
+                .to_string(),
+        };
+
+        let secret = unlock(lab.encryption()?, key_file)?;
+
+        lab.snapshot(snapshot, secret.as_ref())?;
+
+        cache.write()?;
+
+        Ok(())
+    }
+
+    pub fn snapshots(name: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        println!();
+
+        for snapshot in &lab.snapshots {
+            println!(
+                "{} = {}",
+                "name".green().bold(),
+                snapshot.name.cyan().bold()
+            );
+            println!("{}", "--------------------------".blue().bold());
+            println!(
+                "{} -> {}",
+                "created".green(),
+                timestamp(snapshot.created).cyan()
+            );
+            let (label, value) = match snapshot.stored {
+                true => ("tree", &snapshot.sha256),
+                false => ("image", &snapshot.path),
+            };
+            println!("{} -> {}", label.green(), value.cyan());
+            println!(
+                "{} -> {} bytes",
+                "size".green(),
+                snapshot.size.to_string().cyan()
+            );
+            if !snapshot.stored {
+                println!("{} -> {}", "sha256".green(), snapshot.sha256.cyan());
+            }
+            print!("\n\n");
+        }
+
+        stdout().flush().str_result()?;
+
+        Ok(())
+    }
+
+    pub fn rollback(
+        name: String,
+        snapshot: String,
+        key_file: Option<String>,
+    ) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        if lab.drive_letter.is_some() {
+            return Err("Lab is mounted!".to_string());
+        }
+
+        let secret = unlock(lab.snapshot_encryption(&snapshot)?, key_file)?;
+
+        lab.rollback(&snapshot, secret.as_ref())?;
+
+        Ok(())
+    }
+
+    pub fn remove_snapshot(name: String, snapshot: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        cache.search(&name)?.remove_snapshot(&snapshot)?;
+
+        cache.write()?;
+
+        Ok(())
+    }
+
+    pub fn verify(name: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        match lab.verify_image()? {
+            true => println!("{}", "Image verified!".green().bold()),
+            false => println!("{}", "No checksum recorded for image!".yellow().bold()),
+        }
+
+        match lab.verify_snapshots()? {
+            0 => {}
+            count => println!(
+                "{} {}",
+                count.to_string().cyan(),
+                "snapshot(s) verified!".green().bold()
+            ),
+        }
+
+        lab.verify_signature(&Settings::load(settings_path())?.signing)?;
+
+        if let Some(signer) = &lab.signer {
+            println!(
+                "{} {}",
+                "Signed by trusted key".green().bold(),
+                signer.cyan()
+            );
+        }
+
+        cache.write()?;
+
+        Ok(())
+    }
+
+    pub fn sign(name: String, key: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        let public = lab.sign_image(&key)?;
+
+        println!("{} {}", "Image signed by".green().bold(), public.cyan());
+
+        Ok(())
+    }
+
+    pub fn gen_key(key: String) -> Result<(), String> {
+        let public = signature::generate(&key)?;
+
+        println!("{} {}", "Public key:".green().bold(), public.cyan());
+
+        Ok(())
+    }
+
+    // blobs stay in the store until no lab refers to them and this runs
+    pub fn gc() -> Result<(), String> {
+        // the cache is read only once the store is locked, so every finished import is in it
+        let (removed, freed) = Store::default().gc(|| {
+            let mut trees: Vec<String> = Vec::new();
+
+            for lab in Cache::load(cache_path())? {
+                trees.extend(lab.tree);
+                trees.extend(
+                    lab.snapshots
+                        .into_iter()
+                        .filter(|s| s.stored)
+                        .map(|s| s.sha256),
+                );
+            }
+
+            Ok(trees)
+        })?;
+
+        println!(
+            "{} {} blob(s), {}",
+            "Removed".green().bold(),
+            removed.to_string().cyan(),
+            bytes(freed).cyan()
+        );
 
         Ok(())
     }
@@ -332,10 +1247,14 @@ pub mod manage {
     pub fn remove(name: String) -> Result<(), String> {
         let mut cache = Cache::load(cache_path())?;
 
-        if let Some(_) = &cache.search(&name)?.drive_letter {
+        let lab = cache.search(&name)?;
+
+        if let Some(_) = &lab.drive_letter {
             return Err("Lab is mounted!".to_string());
         }
 
+        lab.remove_snapshots()?;
+
         cache.remove(&name)?;
         cache.write()?;
 
@@ -352,6 +1271,8 @@ pub mod manage {
         }
 
         lab.image_path = Some(image);
+        lab.tree = None;
+        lab.record_image()?;
 
         cache.write()?;
 
@@ -364,11 +1285,115 @@ pub mod manage {
         let lab = cache.search(&name)?;
         lab.read_config(&path)?;
 
+        warn(&lab.config);
+
         cache.write()?;
 
         Ok(())
     }
 
+    pub fn export(name: String, file: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        if lab.expanded_path.is_some() {
+            println!(
+                "{}",
+                "Lab is expanded, changes not yet repacked are left out!"
+                    .yellow()
+                    .bold()
+            );
+        }
+
+        lab.export(&file)?;
+
+        println!(
+            "{} {} {} {}",
+            "Exported".green().bold(),
+            name.cyan().bold(),
+            "to".green(),
+            file.cyan()
+        );
+
+        Ok(())
+    }
+
+    pub fn export_config(name: String, path: Option<String>) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        let lab = cache.search(&name)?;
+
+        match path {
+            Some(path) => lab.write_config(&path)?,
+            None => {
+                print!("{}", lab.config_string()?);
+
+                stdout().flush().str_result()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn app_add(name: String, app: String, changes: AppChanges) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        cache.search(&name)?.add_app(app, changes)?;
+
+        check_and_write(cache, &name)
+    }
+
+    pub fn app_edit(name: String, app: String, changes: AppChanges) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        cache.search(&name)?.edit_app(&app, changes)?;
+
+        check_and_write(cache, &name)
+    }
+
+    pub fn app_remove(name: String, app: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        cache.search(&name)?.remove_app(&app)?;
+
+        check_and_write(cache, &name)
+    }
+
+    pub fn env_set(name: String, app: String, key: String, value: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        cache.search(&name)?.set_env(&app, key, value)?;
+
+        check_and_write(cache, &name)
+    }
+
+    pub fn env_unset(name: String, app: String, key: String) -> Result<(), String> {
+        let mut cache = Cache::load(cache_path())?;
+
+        cache.search(&name)?.unset_env(&app, &key)?;
+
+        check_and_write(cache, &name)
+    }
+
+    // edits are only persisted if the resulting config is still valid
+    fn check_and_write(mut cache: Cache, name: &str) -> Result<(), String> {
+        let lab = cache.search(name)?;
+
+        validate::check_config(&lab.config).map_err(|problems| problems.join("\n"))?;
+
+        warn(&lab.config);
+
+        cache.write()
+    }
+
+    // problems that don't stop a config from being used
+    fn warn(config: &LabConfig) {
+        for warning in validate::warnings(config) {
+            println!("{}", warning.yellow());
+        }
+    }
+
     pub fn mount(name: String, drive_letter: String) -> Result<(), String> {
         let mut cache = Cache::load(cache_path())?;
 
@@ -391,9 +1416,91 @@ pub mod manage {
         Ok(())
     }
 
+    // only asks for a secret when the image is actually encrypted
+    fn unlock(kind: Option<KeyKind>, key_file: Option<String>) -> Result<Option<Secret>, String> {
+        match (kind, key_file) {
+            (None, _) => Ok(None),
+            (Some(KeyKind::KeyFile), Some(key_file)) => Ok(Some(Secret::KeyFile(key_file))),
+            (Some(KeyKind::KeyFile), None) => {
+                Err("Image is encrypted with a key file!".to_string())
+            }
+            (Some(KeyKind::Passphrase), _) => Ok(Some(Secret::Passphrase(passphrase(false)?))),
+        }
+    }
+
+    fn passphrase(confirm: bool) -> Result<String, String> {
+        if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+            return Ok(passphrase);
+        }
+
+        // typed without echo, straight from the terminal
+        let prompt = |message: &str| -> Result<String, String> {
+            rpassword::prompt_password(message.green().bold()).str_result()
+        };
+
+        let passphrase = prompt("Passphrase: ")?;
+
+        if passphrase.is_empty() {
+            return Err("Passphrase is empty!".to_string());
+        }
+
+        if confirm && !prompt("Confirm passphrase: ")?.eq(&passphrase) {
+            return Err("Passphrases do not match!".to_string());
+        }
+
+        Ok(passphrase)
+    }
+
+    fn bytes(bytes: u64) -> String {
+        let units = ["B", "KB", "MB", "GB", "TB"];
+
+        let mut size = bytes as f64;
+        let mut unit = 0;
+
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+
+        match unit {
+            0 => format!("{} {}", bytes, units[0]),
+            _ => format!("{:.1} {}", size, units[unit]),
+        }
+    }
+
+    // unix seconds as a utc date and time, without pulling in a date crate
+    fn timestamp(secs: u64) -> String {
+        let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            rest / 3600,
+            rest % 3600 / 60,
+            rest % 60
+        )
+    }
+
     #[inline(always)]
     fn cache_path() -> String {
         CACHE_PATH.to_string()
         // env::var("APPDATA").unwrap() + "\\" + CACHE_PATH_APPDATA
     }
+
+    #[inline(always)]
+    fn settings_path() -> String {
+        SETTINGS_PATH.to_string()
+    }
 }
@@ -0,0 +1,577 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, remove_file, rename, File, OpenOptions},
+    io::{ErrorKind, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder};
+
+use crate::{
+    cmd::StrResult,
+    crypto::{EncryptWriter, Secret},
+    image::digest,
+};
+
+// the manifest describes the image as it was expanded, so repack can tell what changed.
+// it sits next to the image and is only trusted while the image checksum still matches.
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub image_sha256: String,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub path: String,
+    #[serde(default)]
+    pub dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(default)]
+    pub sha256: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Full,
+    Append,
+    Rebuild,
+    Layer,
+    Store,
+    Unchanged,
+}
+
+impl Mode {
+    #[inline(always)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Full => "full",
+            Mode::Append => "append",
+            Mode::Rebuild => "rebuild",
+            Mode::Layer => "top layer",
+            Mode::Store => "store",
+            Mode::Unchanged => "unchanged",
+        }
+    }
+}
+
+pub struct Report {
+    pub mode: Mode,
+    pub rewritten: usize,
+    pub rewritten_bytes: u64,
+    pub reused: usize,
+    pub reused_bytes: u64,
+    pub removed: usize,
+}
+
+// a file on disk under the expanded folder
+pub struct Found {
+    pub path: String,
+    pub source: PathBuf,
+    pub dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    pub link: Option<String>,
+}
+
+#[inline(always)]
+fn manifest_path(image_path: &str) -> String {
+    image_path.to_string() + ".manifest"
+}
+
+pub fn record(image_path: &str, expanded_path: &str, image_sha256: &str) -> Result<(), String> {
+    let mut entries = Vec::new();
+
+    for found in walk(expanded_path)? {
+        let sha256 = match found.dir {
+            true => String::new(),
+            false => hash(&found.source)?,
+        };
+
+        entries.push(Entry {
+            path: found.path,
+            dir: found.dir,
+            size: found.size,
+            mtime: found.mtime,
+            sha256,
+        });
+    }
+
+    let manifest = Manifest {
+        image_sha256: image_sha256.to_string(),
+        entries,
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(manifest_path(image_path))
+        .str_result()?;
+
+    file.write_all(toml::to_string(&manifest).str_result()?.as_bytes())
+        .str_result()?;
+    file.sync_all().str_result()?;
+
+    Ok(())
+}
+
+// None when there is no manifest or it belongs to another image
+pub fn load(image_path: &str, image_sha256: Option<&str>) -> Result<Option<Manifest>, String> {
+    let source = match fs::read_to_string(manifest_path(image_path)) {
+        Ok(source) => source,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let manifest: Manifest = toml::from_str(&source).str_result()?;
+
+    match image_sha256 == Some(manifest.image_sha256.as_str()) {
+        true => Ok(Some(manifest)),
+        false => Ok(None),
+    }
+}
+
+pub fn remove(image_path: &str) -> Result<(), String> {
+    match remove_file(manifest_path(image_path)) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// what a full repack of the folder amounts to
+pub fn full_report(expanded_path: &str) -> Result<Report, String> {
+    let found = walk(expanded_path)?;
+
+    Ok(Report {
+        mode: Mode::Full,
+        rewritten: found.iter().filter(|f| !f.dir).count(),
+        rewritten_bytes: found.iter().map(|f| f.size).sum(),
+        reused: 0,
+        reused_bytes: 0,
+        removed: 0,
+    })
+}
+
+// changed files are appended when nothing was removed and the image stays plain,
+// otherwise the archive is rebuilt, copying unchanged entries straight from the old image
+pub fn repack(
+    image_path: &str,
+    expanded_path: &str,
+    manifest: &Manifest,
+    secret: Option<&Secret>,
+) -> Result<Report, String> {
+    let recorded: HashMap<&str, &Entry> = manifest
+        .entries
+        .iter()
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+
+    let found = walk(expanded_path)?;
+
+    let mut changed: Vec<&Found> = Vec::new();
+    let mut unchanged: HashMap<&str, &Found> = HashMap::new();
+
+    for f in &found {
+        let same = match recorded.get(f.path.as_str()) {
+            Some(e) if e.dir || f.dir => e.dir == f.dir,
+            Some(e) if e.size != f.size => false,
+            Some(e) if e.mtime == f.mtime => true,
+            // touched but maybe not modified
+            Some(e) => hash(&f.source)?.eq(&e.sha256),
+            None => false,
+        };
+
+        match same {
+            true => {
+                unchanged.insert(&f.path, f);
+            }
+            false => changed.push(f),
+        }
+    }
+
+    let present: HashSet<&str> = found.iter().map(|f| f.path.as_str()).collect();
+
+    let removed = manifest
+        .entries
+        .iter()
+        .filter(|e| !present.contains(e.path.as_str()))
+        .count();
+
+    let mut report = Report {
+        mode: Mode::Unchanged,
+        rewritten: changed.iter().filter(|f| !f.dir).count(),
+        rewritten_bytes: changed.iter().map(|f| f.size).sum(),
+        reused: unchanged.values().filter(|f| !f.dir).count(),
+        reused_bytes: unchanged.values().map(|f| f.size).sum(),
+        removed,
+    };
+
+    if removed == 0 && secret.is_none() {
+        if !changed.is_empty() {
+            append(image_path, &changed)?;
+
+            report.mode = Mode::Append;
+        }
+
+        return Ok(report);
+    }
+
+    rebuild(image_path, &changed, &unchanged, secret)?;
+
+    report.mode = Mode::Rebuild;
+
+    Ok(report)
+}
+
+fn append(image_path: &str, changed: &[&Found]) -> Result<(), String> {
+    // the two zero blocks closing the archive are cut off and written again after the delta
+    let mut end = 0;
+
+    let mut archive = Archive::new(File::open(image_path).str_result()?);
+
+    for entry in archive.entries().str_result()? {
+        let entry = entry.str_result()?;
+
+        end = entry.raw_file_position()
+            + entry.header().entry_size().str_result()?.div_ceil(512) * 512;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(image_path)
+        .str_result()?;
+
+    file.set_len(end).str_result()?;
+    file.seek(SeekFrom::Start(end)).str_result()?;
+
+    let mut builder = Builder::new(file);
+
+    for f in changed {
+        add(&mut builder, f)?;
+    }
+
+    builder.into_inner().str_result()?.sync_all().str_result()
+}
+
+fn rebuild(
+    image_path: &str,
+    changed: &[&Found],
+    unchanged: &HashMap<&str, &Found>,
+    secret: Option<&Secret>,
+) -> Result<(), String> {
+    // earlier deltas may have appended newer copies, only the last one is current
+    let mut last: HashMap<String, usize> = HashMap::new();
+
+    let mut archive = Archive::new(File::open(image_path).str_result()?);
+
+    for (i, entry) in archive.entries().str_result()?.enumerate() {
+        last.insert(normalize(&entry.str_result()?.path().str_result()?), i);
+    }
+
+    let temporary = image_path.to_string() + ".repack";
+
+    write_archive(&temporary, secret, |builder| {
+        copy(image_path, builder, changed, unchanged, &last)
+    })?;
+
+    rename(&temporary, image_path).str_result()
+}
+
+// the archive is closed, sealed when encrypted and synced before returning
+pub fn write_archive(
+    path: &str,
+    secret: Option<&Secret>,
+    fill: impl FnOnce(&mut Builder<&mut dyn Write>) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .str_result()?;
+
+    match secret {
+        Some(secret) => {
+            let mut writer = EncryptWriter::new(file, secret)?;
+
+            let mut builder = Builder::new(&mut writer as &mut dyn Write);
+            fill(&mut builder)?;
+            builder.finish().str_result()?;
+            drop(builder);
+
+            writer.finish()?.sync_all().str_result()
+        }
+        None => {
+            let mut builder = Builder::new(&mut file as &mut dyn Write);
+            fill(&mut builder)?;
+            builder.finish().str_result()?;
+            drop(builder);
+
+            file.sync_all().str_result()
+        }
+    }
+}
+
+fn copy(
+    image_path: &str,
+    builder: &mut Builder<&mut dyn Write>,
+    changed: &[&Found],
+    unchanged: &HashMap<&str, &Found>,
+    last: &HashMap<String, usize>,
+) -> Result<(), String> {
+    let mut archive = Archive::new(File::open(image_path).str_result()?);
+
+    for (i, entry) in archive.entries().str_result()?.enumerate() {
+        let mut entry = entry.str_result()?;
+        let path = normalize(&entry.path().str_result()?);
+
+        if !unchanged.contains_key(path.as_str()) || last.get(&path) != Some(&i) {
+            continue;
+        }
+
+        let mut header = entry.header().clone();
+
+        builder
+            .append_data(&mut header, &path, &mut entry)
+            .str_result()?;
+    }
+
+    for f in changed {
+        add(builder, f)?;
+    }
+
+    Ok(())
+}
+
+pub fn add<W: Write>(builder: &mut Builder<W>, f: &Found) -> Result<(), String> {
+    match f.dir {
+        true => builder.append_dir(&f.path, &f.source).str_result(),
+        false => builder
+            .append_path_with_name(&f.source, &f.path)
+            .str_result(),
+    }
+}
+
+// symlinks are followed, like when the whole folder is archived
+#[inline(always)]
+pub fn walk(expanded_path: &str) -> Result<Vec<Found>, String> {
+    walk_with(expanded_path, true)
+}
+
+// symlinks are kept as they are, with their target, and never descended into
+#[inline(always)]
+pub fn walk_links(expanded_path: &str) -> Result<Vec<Found>, String> {
+    walk_with(expanded_path, false)
+}
+
+fn walk_with(expanded_path: &str, follow: bool) -> Result<Vec<Found>, String> {
+    let mut found = Vec::new();
+    let mut pending = vec![PathBuf::from(expanded_path)];
+
+    while let Some(dir) = pending.pop() {
+        for item in fs::read_dir(&dir).str_result()? {
+            let source = item.str_result()?.path();
+
+            let metadata = match follow {
+                true => fs::metadata(&source),
+                false => fs::symlink_metadata(&source),
+            }
+            .str_result()?;
+
+            let link = match metadata.is_symlink() {
+                true => Some(
+                    fs::read_link(&source)
+                        .str_result()?
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                false => None,
+            };
+
+            let path = normalize(source.strip_prefix(expanded_path).str_result()?);
+
+            let mtime = metadata
+                .modified()
+                .str_result()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            if metadata.is_dir() {
+                pending.push(source.clone());
+            }
+
+            found.push(Found {
+                path,
+                dir: metadata.is_dir(),
+                size: match metadata.is_dir() {
+                    true => 0,
+                    false => metadata.len(),
+                },
+                mtime,
+                source,
+                link,
+            });
+        }
+    }
+
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(found)
+}
+
+#[inline(always)]
+pub fn hash(path: &Path) -> Result<String, String> {
+    digest(&path.to_string_lossy()).map(|(sha256, _)| sha256)
+}
+
+// archive and folder paths compared as `a/b/c`
+pub fn normalize(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+    use crate::testing::Scratch;
+
+    // packs the folder and records it, the way a lab is left after expand
+    fn expanded(scratch: &Scratch) -> (String, String, Manifest) {
+        let image = scratch.path("lab.tar");
+        let folder = scratch.path("lab");
+
+        scratch.write("lab/bin/tool", "tool");
+        scratch.write("lab/data/a.txt", "first");
+        scratch.write("lab/data/b.txt", "second");
+
+        write_archive(&image, None, |builder| {
+            builder.append_dir_all(".", &folder).str_result()
+        })
+        .unwrap();
+
+        let (sha256, _) = digest(&image).unwrap();
+        record(&image, &folder, &sha256).unwrap();
+
+        let manifest = load(&image, Some(&sha256)).unwrap().unwrap();
+
+        (image, folder, manifest)
+    }
+
+    // the last copy of every file, like unpacking does
+    fn contents(image: &str) -> HashMap<String, String> {
+        let mut contents = HashMap::new();
+        let mut archive = Archive::new(File::open(image).unwrap());
+
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+
+            if entry.header().entry_type().is_file() {
+                let path = normalize(&entry.path().unwrap());
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+
+                contents.insert(path, content);
+            }
+        }
+
+        contents
+    }
+
+    #[test]
+    fn untouched_folder_is_left_alone() {
+        let scratch = Scratch::new();
+        let (image, folder, manifest) = expanded(&scratch);
+
+        let report = repack(&image, &folder, &manifest, None).unwrap();
+
+        assert!(report.mode == Mode::Unchanged);
+        assert_eq!((report.rewritten, report.reused, report.removed), (0, 3, 0));
+    }
+
+    #[test]
+    fn changed_files_are_appended() {
+        let scratch = Scratch::new();
+        let (image, folder, manifest) = expanded(&scratch);
+
+        scratch.write("lab/data/a.txt", "first, edited");
+        scratch.write("lab/data/c.txt", "third");
+
+        let report = repack(&image, &folder, &manifest, None).unwrap();
+
+        assert!(report.mode == Mode::Append);
+        assert_eq!((report.rewritten, report.reused, report.removed), (2, 2, 0));
+
+        let contents = contents(&image);
+        assert_eq!(contents["data/a.txt"], "first, edited");
+        assert_eq!(contents["data/c.txt"], "third");
+    }
+
+    #[test]
+    fn removed_files_rebuild_the_image() {
+        let scratch = Scratch::new();
+        let (image, folder, manifest) = expanded(&scratch);
+
+        fs::remove_file(scratch.path("lab/data/b.txt")).unwrap();
+
+        let report = repack(&image, &folder, &manifest, None).unwrap();
+
+        assert!(report.mode == Mode::Rebuild);
+        assert_eq!(report.removed, 1);
+
+        let contents = contents(&image);
+        assert!(!contents.contains_key("data/b.txt"));
+        assert_eq!(contents["bin/tool"], "tool");
+    }
+
+    #[test]
+    fn touched_files_are_compared_by_hash() {
+        let scratch = Scratch::new();
+        let (image, folder, manifest) = expanded(&scratch);
+
+        let later = SystemTime::now() + Duration::from_secs(3600);
+
+        OpenOptions::new()
+            .write(true)
+            .open(scratch.path("lab/data/a.txt"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let report = repack(&image, &folder, &manifest, None).unwrap();
+
+        assert!(report.mode == Mode::Unchanged);
+        assert_eq!(report.rewritten, 0);
+    }
+
+    #[test]
+    fn manifest_of_another_image_is_ignored() {
+        let scratch = Scratch::new();
+        let (image, _, _) = expanded(&scratch);
+
+        assert!(load(&image, Some("0000")).unwrap().is_none());
+        assert!(load(&scratch.path("other.tar"), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(normalize(Path::new("./a/b/../c")), "a/b/c");
+        assert_eq!(normalize(Path::new("/a//b/")), "a/b");
+    }
+}
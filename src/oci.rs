@@ -0,0 +1,847 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{remove_file, rename, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::{
+    cmd::StrResult,
+    image::{App, Env, LabConfig, Sandbox},
+    layer, manifest,
+};
+
+// `docker save` lists the layers in `manifest.json`, an oci layout in `index.json`.
+// either way they are applied bottom first into a single plain tar image.
+
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ImageManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    config: Option<ContainerConfig>,
+    rootfs: RootFs,
+}
+
+// digests of the uncompressed layers, bottom first
+#[derive(Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfig {
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    env: Option<Vec<String>>,
+    working_dir: Option<String>,
+}
+
+// where the layers and the config sit inside the tarball, and what the image is called
+struct Located {
+    config: String,
+    layers: Vec<String>,
+    reference: Option<String>,
+}
+
+// writes the flattened image and returns a starter config for it
+pub fn import(tarball: &str, image_path: &str) -> Result<LabConfig, String> {
+    let tarball = Tarball::index(tarball)?;
+    let located = locate(&tarball)?;
+
+    let config: ImageConfig =
+        serde_json::from_slice(&tarball.read(&located.config)?).str_result()?;
+
+    let diff_ids = config.rootfs.diff_ids;
+
+    if diff_ids.len() != located.layers.len() {
+        return Err(format!(
+            "{}: image config lists {} layers, found {}!",
+            tarball.path,
+            diff_ids.len(),
+            located.layers.len()
+        ));
+    }
+
+    let mut written = HashMap::new();
+    let temporary = image_path.to_string() + ".oci";
+
+    let result = manifest::write_archive(&temporary, None, |builder| {
+        let mut hidden = Hidden::default();
+        // hardlinks point at entries that may only be written by a lower layer
+        let mut hardlinks = Vec::new();
+
+        // the top layer goes first, whatever it holds is final, so each layer is read once
+        for (i, name) in located.layers.iter().enumerate().rev() {
+            tarball.with_layer(name, &diff_ids[i], |archive| {
+                let mut flattening = Flattening {
+                    builder: &mut *builder,
+                    written: &mut written,
+                    hidden: &mut hidden,
+                    hardlinks: &mut hardlinks,
+                };
+
+                flattening.layer(i, archive)
+            })?;
+        }
+
+        for (mut header, path, target) in hardlinks {
+            match written.get(&target) {
+                Some(w) if !w.dir => {}
+                _ => return Err(format!("{}: link target {} is gone!", path, target)),
+            }
+
+            builder
+                .append_link(&mut header, &path, &target)
+                .str_result()?;
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        remove_file(&temporary).ok();
+
+        return Err(e);
+    }
+
+    rename(&temporary, image_path).str_result()?;
+
+    let files: HashSet<&str> = written.keys().map(|p| p.as_str()).collect();
+
+    Ok(starter(
+        &tarball.path,
+        located.reference,
+        config.config.unwrap_or_default(),
+        &files,
+    ))
+}
+
+// the layer that wrote a path
+struct Written {
+    layer: usize,
+    dir: bool,
+}
+
+// what the layers above took away from the ones below
+#[derive(Default)]
+struct Hidden {
+    deleted: HashSet<String>,
+    opaque: HashSet<String>,
+}
+
+struct Flattening<'a, 'b> {
+    builder: &'a mut Builder<&'b mut dyn Write>,
+    written: &'a mut HashMap<String, Written>,
+    hidden: &'a mut Hidden,
+    hardlinks: &'a mut Vec<(Header, String, String)>,
+}
+
+impl Flattening<'_, '_> {
+    fn layer(&mut self, layer: usize, archive: &mut Archive<&mut dyn Read>) -> Result<(), String> {
+        // whiteouts only take away from the layers below
+        let mut deleted = Vec::new();
+        let mut opaque = Vec::new();
+
+        for entry in archive.entries().str_result()? {
+            let mut entry = entry.str_result()?;
+            let path = manifest::normalize(&entry.path().str_result()?);
+
+            if let Some(folder) = layer::opaque(&path) {
+                opaque.push(folder);
+
+                continue;
+            }
+
+            if let Some(gone) = layer::whited_out(&path)? {
+                deleted.push(gone);
+
+                continue;
+            }
+
+            if path.is_empty() || self.covered(&path, layer) {
+                continue;
+            }
+
+            let mut header = entry.header().clone();
+            let dir = header.entry_type().is_dir();
+
+            match header.entry_type() {
+                EntryType::Symlink => {
+                    let target = match entry.link_name().str_result()? {
+                        Some(target) => target.into_owned(),
+                        None => continue,
+                    };
+
+                    self.builder
+                        .append_link(&mut header, &path, target)
+                        .str_result()?;
+                }
+                EntryType::Link => match entry.link_name().str_result()? {
+                    Some(target) => {
+                        let target = manifest::normalize(&target);
+
+                        self.hardlinks.push((header, path.clone(), target));
+                    }
+                    None => continue,
+                },
+                EntryType::XGlobalHeader | EntryType::XHeader => continue,
+                _ => {
+                    self.builder
+                        .append_data(&mut header, &path, &mut entry)
+                        .str_result()?;
+                }
+            }
+
+            self.written.insert(path, Written { layer, dir });
+        }
+
+        self.hidden.deleted.extend(deleted);
+        self.hidden.opaque.extend(opaque);
+
+        Ok(())
+    }
+
+    // whether a layer above already wrote the path, or took it away; a file above a folder
+    // takes the folder's contents along, a folder above a folder keeps them
+    fn covered(&self, path: &str, layer: usize) -> bool {
+        let above = |p: &str| self.written.get(p).filter(|w| w.layer != layer);
+
+        if above(path).is_some() || self.hidden.deleted.contains(path) {
+            return true;
+        }
+
+        let mut parent = path;
+
+        while !parent.is_empty() {
+            parent = parent.rsplit_once('/').map(|(p, _)| p).unwrap_or("");
+
+            if self.hidden.opaque.contains(parent)
+                || self.hidden.deleted.contains(parent)
+                || above(parent).is_some_and(|w| !w.dir)
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+fn locate(tarball: &Tarball) -> Result<Located, String> {
+    if let Ok(source) = tarball.read("manifest.json") {
+        let manifests: Vec<DockerManifest> = serde_json::from_slice(&source).str_result()?;
+
+        let manifest = match manifests.into_iter().next() {
+            Some(manifest) => manifest,
+            None => return Err("Tarball holds no image!".to_string()),
+        };
+
+        return Ok(Located {
+            config: manifest.config,
+            layers: manifest.layers,
+            reference: manifest.repo_tags.and_then(|tags| tags.into_iter().next()),
+        });
+    }
+
+    let index: Index = serde_json::from_slice(
+        &tarball
+            .read("index.json")
+            .map_err(|_| "Tarball is neither a docker save nor an oci layout!".to_string())?,
+    )
+    .str_result()?;
+
+    let mut reference = None;
+    let mut manifests = index.manifests;
+
+    // nested indexes, e.g. one per platform, are followed down to the first image
+    loop {
+        let descriptor = match manifests.into_iter().next() {
+            Some(descriptor) => descriptor,
+            None => return Err("Tarball holds no image!".to_string()),
+        };
+
+        reference = reference.or_else(|| {
+            descriptor
+                .annotations
+                .get("io.containerd.image.name")
+                .or(descriptor
+                    .annotations
+                    .get("org.opencontainers.image.ref.name"))
+                .cloned()
+        });
+
+        let source = tarball.read(&blob_path(&descriptor.digest))?;
+
+        match descriptor.media_type.as_deref() {
+            Some(media_type) if INDEX_MEDIA_TYPES.contains(&media_type) => {
+                manifests = serde_json::from_slice::<Index>(&source)
+                    .str_result()?
+                    .manifests;
+            }
+            _ => {
+                let manifest: ImageManifest = serde_json::from_slice(&source).str_result()?;
+
+                return Ok(Located {
+                    config: blob_path(&manifest.config.digest),
+                    layers: manifest
+                        .layers
+                        .iter()
+                        .map(|l| blob_path(&l.digest))
+                        .collect(),
+                    reference,
+                });
+            }
+        }
+    }
+}
+
+// the entrypoint and cmd become the lab's only app, run from the image's working dir.
+// the image brings its own system along, so the sandbox puts it at the root
+fn starter(
+    tarball: &str,
+    reference: Option<String>,
+    container: ContainerConfig,
+    files: &HashSet<&str>,
+) -> LabConfig {
+    let (name, version) = match reference.as_deref().map(split_reference) {
+        Some((name, version)) => (name, version),
+        None => (
+            Path::new(tarball)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or("lab".to_string()),
+            None,
+        ),
+    };
+
+    let envs: Vec<Env> = container
+        .env
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|e| e.split_once('='))
+        .map(|(key, value)| Env {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+        .collect();
+
+    let mut line = container.entrypoint.unwrap_or_default();
+    line.extend(container.cmd.unwrap_or_default());
+
+    if line.is_empty() {
+        line.push("/bin/sh".to_string());
+    }
+
+    let command = line.remove(0);
+
+    // bare commands are looked up along the image's own PATH
+    let command = match command.starts_with('/') {
+        true => command,
+        false => envs
+            .iter()
+            .find(|e| e.key.eq("PATH"))
+            .map(|e| e.value.as_str())
+            .unwrap_or("/usr/local/bin:/usr/bin:/bin")
+            .split(':')
+            .map(|dir| format!("{}/{}", dir.trim_end_matches('/'), command))
+            .find(|path| files.contains(path.trim_start_matches('/')))
+            .unwrap_or(format!("/{}", command)),
+    };
+
+    let work_dir = match container.working_dir {
+        Some(work_dir) if !work_dir.is_empty() => work_dir,
+        _ => "/".to_string(),
+    };
+
+    LabConfig {
+        description: Some(format!("Imported from {}", tarball)),
+        version,
+        default_app: Some(name.clone()),
+        apps: vec![App {
+            name: name.clone(),
+            command,
+            args: line,
+            work_dir,
+            envs,
+            sandbox: Some(Sandbox {
+                mount_point: "/".to_string(),
+                network: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        name,
+        ..Default::default()
+    }
+}
+
+// `registry/team/tool:1.2` is named tool, version 1.2
+fn split_reference(reference: &str) -> (String, Option<String>) {
+    let reference = reference.split('@').next().unwrap_or(reference);
+    let last = reference.rsplit('/').next().unwrap_or(reference);
+
+    match last.split_once(':') {
+        Some((name, tag)) if !name.is_empty() => (name.to_string(), Some(tag.to_string())),
+        Some((_, tag)) => (tag.to_string(), None),
+        None => (last.to_string(), None),
+    }
+}
+
+#[inline(always)]
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+// the members of a tarball, found in a single pass and read straight from where they sit
+struct Tarball {
+    path: String,
+    members: HashMap<String, (u64, u64)>,
+}
+
+impl Tarball {
+    fn index(path: &str) -> Result<Self, String> {
+        let mut archive = Archive::new(File::open(path).str_result()?);
+        let mut members = HashMap::new();
+
+        for entry in archive.entries_with_seek().str_result()? {
+            let entry = entry.str_result()?;
+
+            members.insert(
+                manifest::normalize(&entry.path().str_result()?),
+                (entry.raw_file_position(), entry.size()),
+            );
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            members,
+        })
+    }
+
+    // blobs are named after their own digest
+    fn read(&self, name: &str) -> Result<Vec<u8>, String> {
+        self.with_member(name, |member| {
+            let mut source = Vec::new();
+            member.read_to_end(&mut source).str_result()?;
+
+            if let Some(expected) = name.strip_prefix("blobs/sha256/") {
+                if hex(Sha256::digest(&source)) != expected {
+                    return Err(format!("{}: {} is corrupted!", self.path, name));
+                }
+            }
+
+            Ok(source)
+        })
+    }
+
+    // layers may be gzipped, whatever their media type says; the digest is of the plain tar
+    fn with_layer(
+        &self,
+        name: &str,
+        diff_id: &str,
+        f: impl FnOnce(&mut Archive<&mut dyn Read>) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let expected = match diff_id.split_once(':') {
+            Some(("sha256", expected)) => expected,
+            _ => return Err(format!("{}: unsupported digest {}", name, diff_id)),
+        };
+
+        self.with_member(name, |member| {
+            let mut reader = BufReader::new(member);
+
+            let sha256 = match reader.fill_buf().str_result()? {
+                [0x1f, 0x8b, ..] => hashed(GzDecoder::new(reader), f)?,
+                [0x28, 0xb5, 0x2f, 0xfd, ..] => {
+                    return Err(format!("{}: zstd layers are not supported!", name))
+                }
+                _ => hashed(reader, f)?,
+            };
+
+            match sha256.eq(expected) {
+                true => Ok(()),
+                false => Err(format!("{}: {} is corrupted!", self.path, name)),
+            }
+        })
+    }
+
+    fn with_member<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&mut dyn Read) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let (offset, size) = match self.members.get(name) {
+            Some(member) => *member,
+            None => return Err(format!("{}: {} not found!", self.path, name)),
+        };
+
+        let mut file = File::open(&self.path).str_result()?;
+        file.seek(SeekFrom::Start(offset)).str_result()?;
+
+        f(&mut file.take(size))
+    }
+}
+
+// hands the archive over and hashes everything read from it, the end of archive included
+fn hashed(
+    reader: impl Read,
+    f: impl FnOnce(&mut Archive<&mut dyn Read>) -> Result<(), String>,
+) -> Result<String, String> {
+    let mut reader = Hashing {
+        reader,
+        hasher: Sha256::new(),
+    };
+
+    f(&mut Archive::new(&mut reader as &mut dyn Read))?;
+    io::copy(&mut reader, &mut io::sink()).str_result()?;
+
+    Ok(hex(reader.hasher.finalize()))
+}
+
+struct Hashing<R> {
+    reader: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buffer)?;
+        self.hasher.update(&buffer[..read]);
+
+        Ok(read)
+    }
+}
+
+#[inline(always)]
+fn hex(digest: impl AsRef<[u8]>) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::read_to_string, path::PathBuf};
+
+    use tar::{Builder, Header};
+
+    use super::*;
+    use crate::{
+        image::{Invocation, Lab},
+        testing::Scratch,
+    };
+
+    // a path ending in `/` is a folder, `=>` makes a hardlink, anything else a file with content
+    fn layer(entries: &[&str]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o755);
+            header.set_size(0);
+
+            if let Some((path, target)) = entry.split_once(" => ") {
+                header.set_entry_type(EntryType::Link);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else if entry.ends_with('/') {
+                header.set_entry_type(EntryType::Directory);
+                builder
+                    .append_data(&mut header, entry, io::empty())
+                    .unwrap();
+            } else {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(entry.len() as u64);
+                builder
+                    .append_data(&mut header, entry, entry.as_bytes())
+                    .unwrap();
+            }
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    // a `docker save` tarball, the layers are listed with their true digests unless told otherwise
+    fn saved(scratch: &Scratch, layers: &[Vec<u8>], diff_ids: Option<Vec<String>>) -> String {
+        let diff_ids = diff_ids.unwrap_or_else(|| {
+            layers
+                .iter()
+                .map(|l| format!("sha256:{}", hex(Sha256::digest(l))))
+                .collect()
+        });
+
+        let config = serde_json::json!({
+            "config": {
+                "Env": ["PATH=/usr/bin:/bin"],
+                "Cmd": ["tool", "--serve"],
+                "WorkingDir": "/srv",
+            },
+            "rootfs": { "type": "layers", "diff_ids": diff_ids },
+        });
+
+        let names: Vec<String> = (0..layers.len())
+            .map(|i| format!("{}/layer.tar", i))
+            .collect();
+
+        let manifest = serde_json::json!([{
+            "Config": "config.json",
+            "RepoTags": ["registry/team/tool:1.2"],
+            "Layers": names,
+        }]);
+
+        let path = scratch.path("saved.tar");
+        let mut builder = Builder::new(File::create(&path).unwrap());
+
+        let mut members = vec![
+            (
+                "manifest.json".to_string(),
+                manifest.to_string().into_bytes(),
+            ),
+            ("config.json".to_string(), config.to_string().into_bytes()),
+        ];
+        members.extend(names.into_iter().zip(layers.iter().cloned()));
+
+        for (name, data) in members {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, name, data.as_slice())
+                .unwrap();
+        }
+
+        builder.finish().unwrap();
+
+        path
+    }
+
+    fn flattened(scratch: &Scratch, layers: &[Vec<u8>]) -> (LabConfig, PathBuf) {
+        let image = scratch.path("image.tar");
+        let config = import(&saved(scratch, layers, None), &image).unwrap();
+
+        let target = scratch.root.join("lab");
+        Archive::new(File::open(&image).unwrap())
+            .unpack(&target)
+            .unwrap();
+
+        (config, target)
+    }
+
+    #[test]
+    fn references_name_the_lab() {
+        assert_eq!(
+            split_reference("registry/team/tool:1.2"),
+            ("tool".to_string(), Some("1.2".to_string()))
+        );
+        assert_eq!(
+            split_reference("localhost:5000/tool"),
+            ("tool".to_string(), None)
+        );
+        assert_eq!(
+            split_reference("tool:1.2@sha256:abc"),
+            ("tool".to_string(), Some("1.2".to_string()))
+        );
+    }
+
+    #[test]
+    fn files_over_folders_take_their_contents_along() {
+        let scratch = Scratch::new();
+        let (_, lab) = flattened(
+            &scratch,
+            &[layer(&["a/", "a/x", "b/", "b/y"]), layer(&["a", "b/z"])],
+        );
+
+        assert!(lab.join("a").is_file());
+        assert!(!lab.join("a/x").exists());
+        assert!(lab.join("b/y").exists());
+        assert!(lab.join("b/z").exists());
+    }
+
+    #[test]
+    fn hardlinks_are_written_after_their_target() {
+        let scratch = Scratch::new();
+        let (_, lab) = flattened(&scratch, &[layer(&["t", "l => t"]), layer(&["t"])]);
+
+        assert_eq!(read_to_string(lab.join("l")).unwrap(), "t");
+    }
+
+    #[test]
+    fn corrupted_layers_are_refused() {
+        let scratch = Scratch::new();
+        let layers = [layer(&["a"])];
+        let tarball = saved(
+            &scratch,
+            &layers,
+            Some(vec![format!("sha256:{}", "0".repeat(64))]),
+        );
+        let image = scratch.path("image.tar");
+
+        assert!(import(&tarball, &image).is_err());
+        assert!(!Path::new(&image).exists());
+        assert!(!Path::new(&(image + ".oci")).exists());
+    }
+
+    #[test]
+    fn starters_run_in_a_sandbox() {
+        let scratch = Scratch::new();
+        let (config, _) = flattened(&scratch, &[layer(&["usr/", "usr/bin/", "usr/bin/tool"])]);
+
+        let app = &config.apps[0];
+
+        assert_eq!(config.name, "tool");
+        assert_eq!(app.command, "/usr/bin/tool");
+        assert_eq!(app.args, vec!["--serve".to_string()]);
+        assert_eq!(app.work_dir, "/srv");
+        assert!(app
+            .sandbox
+            .as_ref()
+            .is_some_and(|s| s.network && s.mount_point.eq("/")));
+        assert_eq!(app.envs[0].value, "/usr/bin:/bin");
+    }
+
+    #[test]
+    fn whiteouts_hide_what_lies_below() {
+        let scratch = Scratch::new();
+        let (_, lab) = flattened(
+            &scratch,
+            &[
+                layer(&["a/", "a/x", "b/", "b/y", "c"]),
+                layer(&["a/", "a/.wh..wh..opq", "a/z", "b/.wh.y", ".wh.c"]),
+                layer(&["c"]),
+            ],
+        );
+
+        assert!(!lab.join("a/x").exists());
+        assert!(lab.join("a/z").exists());
+        assert!(lab.join("b").is_dir());
+        assert!(!lab.join("b/y").exists());
+        assert!(lab.join("c").exists());
+    }
+
+    // a static x86_64 program that exits with 0 when `marker` sits both in its working dir
+    // and in `/srv`, which only holds when the image is the root
+    #[cfg(target_arch = "x86_64")]
+    fn probe() -> Vec<u8> {
+        let code: &[u8] = &[
+            0xb8, 0x15, 0x00, 0x00, 0x00, // mov eax, access
+            0x48, 0x8d, 0x3d, 0x21, 0x00, 0x00, 0x00, // lea rdi, [rip + relative]
+            0x31, 0xf6, // xor esi, esi
+            0x0f, 0x05, // syscall
+            0x89, 0xc3, // mov ebx, eax
+            0xb8, 0x15, 0x00, 0x00, 0x00, // mov eax, access
+            0x48, 0x8d, 0x3d, 0x16, 0x00, 0x00, 0x00, // lea rdi, [rip + absolute]
+            0x31, 0xf6, // xor esi, esi
+            0x0f, 0x05, // syscall
+            0x09, 0xd8, // or eax, ebx
+            0x89, 0xc7, // mov edi, eax
+            0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, exit
+            0x0f, 0x05, // syscall
+        ];
+        let code = [code, b"marker\0/srv/marker\0"].concat();
+
+        let base = 0x400000u64;
+        let size = (64 + 56 + code.len()) as u64;
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend(2u16.to_le_bytes()); // executable
+        elf.extend(0x3eu16.to_le_bytes()); // x86_64
+        elf.extend(1u32.to_le_bytes());
+        elf.extend((base + 64 + 56).to_le_bytes()); // entry, right after the headers
+        elf.extend(64u64.to_le_bytes()); // program headers
+        elf.extend(0u64.to_le_bytes()); // no section headers
+        elf.extend(0u32.to_le_bytes());
+        elf.extend(64u16.to_le_bytes());
+        elf.extend(56u16.to_le_bytes());
+        elf.extend(1u16.to_le_bytes());
+        elf.extend([0; 6]);
+
+        // the whole file is loaded, readable and executable
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(5u32.to_le_bytes());
+        elf.extend(0u64.to_le_bytes());
+        elf.extend(base.to_le_bytes());
+        elf.extend(base.to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(0x1000u64.to_le_bytes());
+
+        elf.extend(&code);
+
+        elf
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn starters_run_in_their_image() {
+        let scratch = Scratch::new();
+
+        let mut builder = Builder::new(Vec::new());
+        for (path, data) in [
+            ("usr/bin/tool", probe()),
+            ("srv/marker", Vec::new()),
+            ("proc/", Vec::new()),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o755);
+            header.set_size(data.len() as u64);
+            header.set_entry_type(match path.ends_with('/') {
+                true => EntryType::Directory,
+                false => EntryType::Regular,
+            });
+            builder
+                .append_data(&mut header, path, data.as_slice())
+                .unwrap();
+        }
+
+        let (config, expanded) = flattened(&scratch, &[builder.into_inner().unwrap()]);
+
+        let mut lab = Lab::from_expanded(expanded.to_string_lossy().to_string());
+        lab.config = config;
+
+        let invocation = Invocation {
+            params: HashMap::new(),
+            profile: None,
+        };
+        let status = lab.run("tool", None, &invocation).unwrap().wait().unwrap();
+
+        assert!(status.success(), "{}", status);
+    }
+}
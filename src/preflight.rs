@@ -0,0 +1,206 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::image::Requires;
+
+// everything missing is reported, not just the first problem
+pub fn check(requires: &Requires, path: &Path) -> Result<(), Vec<String>> {
+    let mut missing: Vec<String> = Vec::new();
+
+    for executable in &requires.executables {
+        if find_executable(executable).is_none() {
+            missing.push(format!("Missing executable: {}", executable));
+        }
+    }
+
+    for file in &requires.files {
+        if !Path::new(file).exists() {
+            missing.push(format!("Missing file: {}", file));
+        }
+    }
+
+    for env in &requires.envs {
+        if env::var_os(env).is_none() {
+            missing.push(format!("Missing env: {}", env));
+        }
+    }
+
+    if let Some(min_free_disk) = requires.min_free_disk {
+        match free_disk(path) {
+            Ok(free) if free / (1024 * 1024) < min_free_disk => missing.push(format!(
+                "Not enough free disk: {} MB available, {} MB required",
+                free / (1024 * 1024),
+                min_free_disk
+            )),
+            Ok(_) => {}
+            Err(e) => missing.push(format!("Could not check free disk: {}", e)),
+        }
+    }
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(missing),
+    }
+}
+
+fn find_executable(name: &str) -> Option<PathBuf> {
+    // a name with a separator is a path, not something to look up
+    if name.contains(['/', '\\']) {
+        let path = PathBuf::from(name);
+
+        return path.is_file().then_some(path);
+    }
+
+    let extensions = executable_extensions();
+
+    env::split_paths(&env::var_os("PATH")?).find_map(|dir| {
+        extensions
+            .iter()
+            .map(|extension| dir.join(name.to_string() + extension))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+#[cfg(windows)]
+fn executable_extensions() -> Vec<String> {
+    let pathext = env::var("PATHEXT").unwrap_or(".COM;.EXE;.BAT;.CMD".to_string());
+
+    let mut extensions = vec![String::new()];
+    extensions.extend(pathext.split(';').map(|e| e.to_lowercase()));
+
+    extensions
+}
+
+#[cfg(not(windows))]
+#[inline(always)]
+fn executable_extensions() -> Vec<String> {
+    vec![String::new()]
+}
+
+// the target of an expand may not exist yet, so the nearest existing ancestor is asked
+#[cfg(any(target_os = "linux", windows))]
+fn existing_ancestor(path: &Path) -> PathBuf {
+    let absolute = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => env::current_dir().unwrap_or_default().join(path),
+    };
+
+    absolute
+        .ancestors()
+        .find(|a| a.exists())
+        .map(Path::to_path_buf)
+        .unwrap_or(absolute)
+}
+
+#[cfg(target_os = "linux")]
+fn free_disk(path: &Path) -> Result<u64, String> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(existing_ancestor(path).as_os_str().as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    match unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } {
+        0 => {
+            let stat = unsafe { stat.assume_init() };
+
+            Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+        }
+        _ => Err(std::io::Error::last_os_error().to_string()),
+    }
+}
+
+#[cfg(windows)]
+fn free_disk(path: &Path) -> Result<u64, String> {
+    use std::{iter::once, os::windows::ffi::OsStrExt, ptr::null_mut};
+
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = existing_ancestor(path)
+        .as_os_str()
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+
+    let mut free: u64 = 0;
+
+    match unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut free, null_mut(), null_mut()) } {
+        0 => Err(std::io::Error::last_os_error().to_string()),
+        _ => Ok(free),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn free_disk(_path: &Path) -> Result<u64, String> {
+    Err("Free disk check is not supported on this platform!".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Scratch;
+
+    fn requires(executables: &[&str], files: &[String], envs: &[&str]) -> Requires {
+        Requires {
+            executables: executables.iter().map(|e| e.to_string()).collect(),
+            files: files.to_vec(),
+            envs: envs.iter().map(|e| e.to_string()).collect(),
+            min_free_disk: None,
+        }
+    }
+
+    #[test]
+    fn everything_missing_is_reported_at_once() {
+        let scratch = Scratch::new();
+        let license = scratch.path("license.lic");
+        let requires = requires(
+            &["laboratory-missing-tool"],
+            std::slice::from_ref(&license),
+            &["LABORATORY_MISSING_ENV"],
+        );
+
+        assert_eq!(
+            check(&requires, &scratch.root).unwrap_err(),
+            [
+                "Missing executable: laboratory-missing-tool".to_string(),
+                format!("Missing file: {}", license),
+                "Missing env: LABORATORY_MISSING_ENV".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn present_requirements_pass() {
+        let scratch = Scratch::new();
+        let license = scratch.write("license.lic", "");
+        let requires = requires(&["sh", "/bin/sh"], &[license], &["PATH"]);
+
+        check(&requires, &scratch.root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn free_disk_is_checked_where_the_lab_will_be() {
+        let scratch = Scratch::new();
+        let target = scratch.root.join("not/expanded/yet");
+
+        assert_eq!(existing_ancestor(&target), scratch.root);
+
+        let mut requires = requires(&[], &[], &[]);
+        requires.min_free_disk = Some(0);
+        check(&requires, &target).unwrap();
+
+        requires.min_free_disk = Some(u64::MAX);
+        let missing = check(&requires, &target).unwrap_err();
+
+        assert!(
+            missing[0].starts_with("Not enough free disk"),
+            "{}",
+            missing[0]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    env,
+    ffi::{CStr, CString},
+    fs::create_dir_all,
+    io,
+    os::unix::process::CommandExt,
+    path::Path,
+    process::Command,
+};
+
+use crate::{cmd::StrResult, image::Sandbox};
+
+const STAGING_DIR: &str = "laboratory-sandbox";
+
+pub fn confine(
+    command: &mut Command,
+    sandbox: &Sandbox,
+    expanded_path: &str,
+    work_dir: &str,
+) -> Result<(), String> {
+    let mount_point = sandbox.mount_point.trim_matches('/');
+
+    // `/proc` of the sandbox is its own, `/` puts the lab itself at the root
+    if sandbox.mount_point.is_empty()
+        || mount_point
+            .split_terminator('/')
+            .any(|c| c.is_empty() || c.eq(".."))
+        || mount_point.split_terminator('/').next() == Some("proc")
+    {
+        return Err("Invalid sandbox mount point!".to_string());
+    }
+
+    let staging = env::temp_dir().join(STAGING_DIR);
+    create_dir_all(&staging).str_result()?;
+    let staging = staging.to_string_lossy().to_string();
+
+    // everything is prepared up front, nothing is allocated after fork
+    let setgroups = c_string("/proc/self/setgroups")?;
+    let uid_map = c_string("/proc/self/uid_map")?;
+    let gid_map = c_string("/proc/self/gid_map")?;
+    let uid_line = format!("0 {} 1", unsafe { libc::getuid() });
+    let gid_line = format!("0 {} 1", unsafe { libc::getgid() });
+
+    let root = c_string("/")?;
+    let tmpfs = c_string("tmpfs")?;
+    let proc = c_string("proc")?;
+    let staging_dir = c_string(&staging)?;
+    let dot = c_string(".")?;
+    let proc_dir = c_string(&(staging.clone() + "/proc"))?;
+    let source = c_string(expanded_path)?;
+
+    let mut mount_dirs = Vec::new();
+    let mut path = staging.clone();
+    for component in mount_point.split_terminator('/') {
+        path = path + "/" + component;
+        mount_dirs.push(c_string(&path)?);
+    }
+    let target = c_string(&path)?;
+
+    let work_dir = c_string(
+        &Path::new("/")
+            .join(mount_point)
+            .join(work_dir.trim_start_matches('/'))
+            .to_string_lossy(),
+    )?;
+
+    let mut namespaces = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+    if !sandbox.network {
+        namespaces |= libc::CLONE_NEWNET;
+    }
+
+    let read_only = sandbox.read_only;
+
+    unsafe {
+        command.pre_exec(move || {
+            check(libc::unshare(namespaces))?;
+
+            write_file(&setgroups, b"deny")?;
+            write_file(&uid_map, uid_line.as_bytes())?;
+            write_file(&gid_map, gid_line.as_bytes())?;
+
+            check(libc::mount(
+                std::ptr::null(),
+                root.as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            check(libc::mount(
+                tmpfs.as_ptr(),
+                staging_dir.as_ptr(),
+                tmpfs.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                std::ptr::null(),
+            ))?;
+
+            for dir in &mount_dirs {
+                check(libc::mkdir(dir.as_ptr(), 0o755))?;
+            }
+
+            check(libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                std::ptr::null(),
+            ))?;
+
+            if read_only {
+                check(libc::mount(
+                    std::ptr::null(),
+                    target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_REC,
+                    std::ptr::null(),
+                ))?;
+            }
+
+            // the first child becomes pid 1 of the new namespace
+            let pid = check(libc::fork())?;
+            if pid != 0 {
+                reap(pid);
+            }
+
+            // killing the reaper takes pid 1, and with it the whole namespace, down too.
+            // spawn() only returns after exec, so the reaper can't be killed before this
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+            // a lab at the root usually brings its own `/proc` folder along
+            if libc::mkdir(proc_dir.as_ptr(), 0o555) == -1
+                && io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST)
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            check(libc::mount(
+                proc.as_ptr(),
+                proc_dir.as_ptr(),
+                proc.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                std::ptr::null(),
+            ))?;
+
+            // the staging folder becomes the root; the old root ends up stacked on top of it
+            // and is detached, so there is nothing left to climb out to, unlike with chroot
+            check(libc::chdir(staging_dir.as_ptr()))?;
+            check(libc::syscall(libc::SYS_pivot_root, dot.as_ptr(), dot.as_ptr()) as libc::c_int)?;
+            check(libc::umount2(dot.as_ptr(), libc::MNT_DETACH))?;
+            check(libc::chdir(work_dir.as_ptr()))?;
+
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+#[inline(always)]
+fn c_string(s: &str) -> Result<CString, String> {
+    CString::new(s).str_result()
+}
+
+#[inline(always)]
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        ret => Ok(ret),
+    }
+}
+
+unsafe fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+    let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    libc::close(fd);
+
+    match written {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+// waits for the sandboxed process on behalf of the spawned child and mirrors its exit status
+unsafe fn reap(pid: libc::pid_t) -> ! {
+    // drop the exec status pipe so spawn() returns once the grandchild has exec'd
+    if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) == -1 {
+        for fd in 3..1024 {
+            libc::close(fd);
+        }
+    }
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) == -1 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            libc::_exit(1);
+        }
+    }
+
+    if libc::WIFEXITED(status) {
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+
+    libc::_exit(128 + libc::WTERMSIG(status));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Scratch;
+
+    // runs in the sandboxed process right before exec, the outcome comes back as the spawn error
+    fn probe(command: &mut Command) {
+        unsafe {
+            command.pre_exec(|| {
+                if libc::access(c"/lab/inside".as_ptr(), libc::F_OK) != 0 {
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+
+                // the textbook chroot escape: chroot into a subfolder, then climb out of it
+                libc::mkdir(c"/escape".as_ptr(), 0o755);
+                libc::chroot(c"/escape".as_ptr());
+
+                for _ in 0..64 {
+                    libc::chdir(c"..".as_ptr());
+                }
+
+                libc::chroot(c".".as_ptr());
+
+                match libc::access(c"/etc/passwd".as_ptr(), libc::F_OK) {
+                    0 => Err(io::Error::from_raw_os_error(libc::EEXIST)),
+                    _ => Err(io::Error::from_raw_os_error(libc::ECANCELED)),
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn the_lab_is_the_whole_world() {
+        let scratch = Scratch::new();
+        scratch.write("lab/inside", "");
+
+        let mut command = Command::new("/lab/missing");
+        confine(&mut command, &Sandbox::default(), &scratch.path("lab"), "/").unwrap();
+        probe(&mut command);
+
+        let error = command.spawn().unwrap_err();
+
+        assert_eq!(error.raw_os_error(), Some(libc::ECANCELED), "{}", error);
+    }
+
+    #[test]
+    fn proc_is_no_mount_point() {
+        let scratch = Scratch::new();
+
+        for mount_point in ["/proc", "proc/lab", "", "a/../b"] {
+            let sandbox = Sandbox {
+                mount_point: mount_point.to_string(),
+                ..Default::default()
+            };
+
+            assert!(confine(&mut Command::new("/x"), &sandbox, &scratch.path(""), "/").is_err());
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::{ErrorKind, Write},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::StrResult;
+
+// the host's policy, read from its own config so no lab can loosen it
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Signing {
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl Signing {
+    pub fn check(&self) -> Result<(), String> {
+        for key in &self.trusted_keys {
+            if key.trim().len() != 64 || !key.trim().chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid trusted key: {:?}", key));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn trusts(&self, key: &str) -> bool {
+        self.trusted_keys
+            .iter()
+            .any(|k| k.trim().eq_ignore_ascii_case(key))
+    }
+}
+
+// a detached signature lives next to the image and covers its sha-256 digest
+#[derive(Serialize, Deserialize)]
+struct Detached {
+    key: String,
+    signature: String,
+}
+
+#[inline(always)]
+pub fn signature_path(image_path: &str) -> String {
+    image_path.to_string() + ".sig"
+}
+
+// the secret key is written as hex to `path` and the public key to `path.pub`
+pub fn generate(path: &str) -> Result<String, String> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).str_result()?;
+
+    let key = SigningKey::from_bytes(&seed);
+    let public = to_hex(key.verifying_key().as_bytes());
+
+    write_new(path, &to_hex(&seed))?;
+    write_new(&(path.to_string() + ".pub"), &public)?;
+
+    Ok(public)
+}
+
+pub fn sign(image_path: &str, sha256: &str, key_path: &str) -> Result<String, String> {
+    let seed: [u8; 32] = from_hex(read_to_string(key_path).str_result()?.trim())?
+        .try_into()
+        .map_err(|_| "Key must be 32 bytes!".to_string())?;
+
+    let key = SigningKey::from_bytes(&seed);
+    let public = to_hex(key.verifying_key().as_bytes());
+
+    let detached = Detached {
+        key: public.clone(),
+        signature: to_hex(&key.sign(sha256.as_bytes()).to_bytes()),
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(signature_path(image_path))
+        .str_result()?;
+
+    file.write_all(toml::to_string(&detached).str_result()?.as_bytes())
+        .str_result()?;
+    file.sync_all().str_result()?;
+
+    Ok(public)
+}
+
+// a tampered image is always refused, missing or untrusted signatures only when required.
+// the trusted key that verified the image is returned
+pub fn verify(image_path: &str, sha256: &str, signing: &Signing) -> Result<Option<String>, String> {
+    let source = match read_to_string(signature_path(image_path)) {
+        Ok(source) => source,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return match signing.required {
+                true => Err("Image is not signed!".to_string()),
+                false => Ok(None),
+            };
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let detached: Detached = toml::from_str(&source).str_result()?;
+
+    if !signing.trusts(&detached.key) {
+        return match signing.required {
+            true => Err(format!(
+                "Image is signed by an untrusted key: {}",
+                detached.key
+            )),
+            false => Ok(None),
+        };
+    }
+
+    let key: [u8; 32] = from_hex(&detached.key)?
+        .try_into()
+        .map_err(|_| "Signature key must be 32 bytes!".to_string())?;
+    let signature: [u8; 64] = from_hex(&detached.signature)?
+        .try_into()
+        .map_err(|_| "Signature must be 64 bytes!".to_string())?;
+
+    VerifyingKey::from_bytes(&key)
+        .str_result()?
+        .verify(sha256.as_bytes(), &Signature::from_bytes(&signature))
+        .map_err(|_| "Image signature is invalid!".to_string())?;
+
+    Ok(Some(detached.key.to_ascii_lowercase()))
+}
+
+fn write_new(path: &str, content: &str) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    file.write_all(content.as_bytes()).str_result()?;
+    file.sync_all().str_result()?;
+
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("Invalid hex: {}", hex));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid hex: {}", hex))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Scratch;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn policy(trusted_keys: Vec<String>, required: bool) -> Signing {
+        Signing {
+            trusted_keys,
+            required,
+        }
+    }
+
+    #[test]
+    fn trusted_signature_names_its_signer() {
+        let scratch = Scratch::new();
+        let image = scratch.write("lab.tar", "image");
+
+        let public = generate(&scratch.path("key")).unwrap();
+        sign(&image, SHA256, &scratch.path("key")).unwrap();
+
+        let signer = verify(&image, SHA256, &policy(vec![public.to_uppercase()], true));
+
+        assert_eq!(signer, Ok(Some(public)));
+    }
+
+    #[test]
+    fn tampered_image_is_refused_even_without_policy() {
+        let scratch = Scratch::new();
+        let image = scratch.write("lab.tar", "image");
+
+        let public = generate(&scratch.path("key")).unwrap();
+        sign(&image, SHA256, &scratch.path("key")).unwrap();
+
+        let tampered = SHA256.replace('9', "8");
+
+        assert!(verify(&image, &tampered, &policy(vec![public], false)).is_err());
+    }
+
+    #[test]
+    fn missing_and_untrusted_signatures_only_fail_when_required() {
+        let scratch = Scratch::new();
+        let image = scratch.write("lab.tar", "image");
+
+        assert_eq!(verify(&image, SHA256, &policy(vec![], false)), Ok(None));
+        assert!(verify(&image, SHA256, &policy(vec![], true)).is_err());
+
+        generate(&scratch.path("key")).unwrap();
+        sign(&image, SHA256, &scratch.path("key")).unwrap();
+
+        assert_eq!(verify(&image, SHA256, &policy(vec![], false)), Ok(None));
+        assert!(verify(&image, SHA256, &policy(vec![], true)).is_err());
+    }
+
+    #[test]
+    fn malformed_trusted_keys_are_rejected() {
+        assert!(policy(vec!["abc".to_string()], false).check().is_err());
+        assert!(policy(vec!["ab".repeat(32)], true).check().is_ok());
+    }
+}
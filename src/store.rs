@@ -0,0 +1,962 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, create_dir_all, remove_dir, remove_file, rename, File, OpenOptions, TryLockError},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType, Header};
+
+use crate::{
+    cmd::StrResult,
+    image::digest,
+    manifest::{self, Mode, Report},
+};
+
+// every file is kept once under `blobs`, named by its sha-256.
+// a stored lab is a tree, itself a blob, listing which blob goes where.
+
+const STORE_PATH: &str = ".laboratory\\Store";
+// whatever is still incoming after this was left behind by an interrupted import
+const STALE: Duration = Duration::from_secs(24 * 60 * 60);
+
+static INCOMING: AtomicUsize = AtomicUsize::new(0);
+
+// stores this process has written to. the shared lock is held until the process exits, by which
+// time whatever it imported is referenced from the cache, so gc can't take blobs out from under it
+static HELD: Mutex<Vec<(PathBuf, File)>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Deserialize)]
+pub struct Tree {
+    pub entries: Vec<Node>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Node {
+    pub path: String,
+    #[serde(default)]
+    pub dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(default)]
+    pub mode: u32,
+    #[serde(default)]
+    pub sha256: String,
+    // symlinks keep their target and no blob
+    #[serde(default)]
+    pub link: Option<String>,
+}
+
+pub struct Store {
+    root: PathBuf,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(STORE_PATH)
+    }
+}
+
+impl Store {
+    #[inline(always)]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    #[inline(always)]
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root.join("blobs").join(&sha256[..2]).join(sha256)
+    }
+
+    // a plain tar image, split per file
+    pub fn import_archive(&self, reader: impl Read) -> Result<(String, Report), String> {
+        let mut archive = Archive::new(reader);
+
+        // appended deltas may repeat a path, only the last one counts
+        let mut nodes: BTreeMap<String, Node> = BTreeMap::new();
+
+        let mut report = report();
+
+        for entry in archive.entries().str_result()? {
+            let mut entry = entry.str_result()?;
+            let path = manifest::normalize(&entry.path().str_result()?);
+
+            if path.is_empty() {
+                continue;
+            }
+
+            let header = entry.header();
+            let kind = header.entry_type();
+            let mtime = header.mtime().str_result()?;
+            let mode = header.mode().str_result()?;
+
+            let node = match kind {
+                EntryType::Directory => Node {
+                    path: path.clone(),
+                    dir: true,
+                    size: 0,
+                    mtime,
+                    mode,
+                    sha256: String::new(),
+                    link: None,
+                },
+                EntryType::Symlink => Node {
+                    path: path.clone(),
+                    dir: false,
+                    size: 0,
+                    mtime,
+                    mode,
+                    sha256: String::new(),
+                    link: match entry.link_name().str_result()? {
+                        Some(target) => Some(target.to_string_lossy().to_string()),
+                        None => return Err(format!("{}: link has no target!", path)),
+                    },
+                },
+                // a hardlink shares its target's blob, which is all a blob store can share
+                EntryType::Link => {
+                    let target = match entry.link_name().str_result()? {
+                        Some(target) => manifest::normalize(&target),
+                        None => return Err(format!("{}: link has no target!", path)),
+                    };
+
+                    match nodes.get(&target) {
+                        Some(node) if !node.dir => {
+                            count(&mut report, node.size, false);
+
+                            Node {
+                                path: path.clone(),
+                                mtime,
+                                mode,
+                                ..node.clone()
+                            }
+                        }
+                        _ => return Err(format!("{}: link target {} is missing!", path, target)),
+                    }
+                }
+                kind if kind.is_file() => {
+                    let (sha256, size, new) = self.put(&mut entry)?;
+
+                    count(&mut report, size, new);
+
+                    Node {
+                        path: path.clone(),
+                        dir: false,
+                        size,
+                        mtime,
+                        mode,
+                        sha256,
+                        link: None,
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "{}: only files, folders and links can be stored!",
+                        path
+                    ))
+                }
+            };
+
+            nodes.insert(path, node);
+        }
+
+        let tree = self.write_tree(nodes.into_values().collect())?;
+
+        Ok((tree, report))
+    }
+
+    // an expanded folder, hashing only what changed since `previous`
+    pub fn import_dir(
+        &self,
+        expanded_path: &str,
+        previous: Option<&str>,
+    ) -> Result<(String, Report), String> {
+        let previous = match previous {
+            Some(previous) => self.load(previous)?,
+            None => Tree {
+                entries: Vec::new(),
+            },
+        };
+
+        let recorded: HashMap<&str, &Node> = previous
+            .entries
+            .iter()
+            .map(|n| (n.path.as_str(), n))
+            .collect();
+
+        let found = manifest::walk_links(expanded_path)?;
+
+        let mut report = report();
+        let mut nodes = Vec::new();
+
+        for f in &found {
+            if f.link.is_some() {
+                nodes.push(Node {
+                    path: f.path.clone(),
+                    dir: false,
+                    size: 0,
+                    mtime: f.mtime,
+                    mode: 0,
+                    sha256: String::new(),
+                    link: f.link.clone(),
+                });
+
+                continue;
+            }
+
+            let sha256 = match (f.dir, recorded.get(f.path.as_str())) {
+                (true, _) => String::new(),
+                (false, Some(n))
+                    if !n.dir
+                        && n.size == f.size
+                        && n.mtime == f.mtime
+                        && self.blob_path(&n.sha256).exists() =>
+                {
+                    count(&mut report, f.size, false);
+
+                    n.sha256.clone()
+                }
+                (false, _) => {
+                    let (sha256, size, new) = self.put(File::open(&f.source).str_result()?)?;
+
+                    count(&mut report, size, new);
+
+                    sha256
+                }
+            };
+
+            nodes.push(Node {
+                path: f.path.clone(),
+                dir: f.dir,
+                size: f.size,
+                mtime: f.mtime,
+                mode: mode(&f.source)?,
+                sha256,
+                link: None,
+            });
+        }
+
+        let present: HashSet<&str> = found.iter().map(|f| f.path.as_str()).collect();
+
+        report.removed = previous
+            .entries
+            .iter()
+            .filter(|n| !present.contains(n.path.as_str()))
+            .count();
+
+        let tree = self.write_tree(nodes)?;
+
+        Ok((tree, report))
+    }
+
+    pub fn load(&self, tree: &str) -> Result<Tree, String> {
+        let path = self.blob_path(tree);
+
+        let (sha256, _) = digest(&path.to_string_lossy()).map_err(|_| "Store tree is missing!")?;
+
+        if !sha256.eq(tree) {
+            return Err("Store tree is corrupted!".to_string());
+        }
+
+        toml::from_str(&fs::read_to_string(path).str_result()?).str_result()
+    }
+
+    // parents sort before their children, so folders exist by the time files land in them.
+    // blobs are hashed on the way out, a corrupted one never makes it into the folder
+    pub fn checkout(&self, tree: &Tree, target: &Path) -> Result<(), String> {
+        create_dir_all(target).str_result()?;
+
+        let mut links: HashSet<&str> = HashSet::new();
+
+        for node in &tree.entries {
+            let destination = target.join(&node.path);
+
+            // nothing is written through a link, it could lead anywhere
+            let mut parent = node.path.as_str();
+            while let Some((above, _)) = parent.rsplit_once('/') {
+                if links.contains(above) {
+                    return Err(format!("{}: lies behind the link {}!", node.path, above));
+                }
+
+                parent = above;
+            }
+
+            if node.dir {
+                create_dir_all(&destination).str_result()?;
+
+                continue;
+            }
+
+            if let Some(link) = &node.link {
+                if let Some(parent) = destination.parent() {
+                    create_dir_all(parent).str_result()?;
+                }
+
+                symlink(link, &destination).map_err(|e| format!("{}: {}", node.path, e))?;
+                links.insert(&node.path);
+
+                continue;
+            }
+
+            if let Some(parent) = destination.parent() {
+                create_dir_all(parent).str_result()?;
+            }
+
+            let blob = File::open(self.blob_path(&node.sha256))
+                .map_err(|e| format!("{}: {}", node.path, e))?;
+            let mut file =
+                File::create(&destination).map_err(|e| format!("{}: {}", node.path, e))?;
+
+            let (sha256, _) = stream(blob, &mut file)?;
+
+            if !sha256.eq(&node.sha256) {
+                drop(file);
+                remove_file(&destination).ok();
+
+                return Err(format!("{}: blob is corrupted!", node.path));
+            }
+
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(node.mtime))
+                .str_result()?;
+            drop(file);
+
+            // last, a read-only mode would refuse the mtime
+            set_mode(&destination, node.mode)?;
+        }
+
+        Ok(())
+    }
+
+    // a plain tar image of the tree, as if it had been packed from a folder
+    pub fn write_image(&self, tree: &str, path: &str) -> Result<(), String> {
+        let tree = self.load(tree)?;
+
+        manifest::write_archive(path, None, |builder| {
+            for node in &tree.entries {
+                let mut header = Header::new_gnu();
+                header.set_mtime(node.mtime);
+                header.set_mode(match (node.mode, node.dir) {
+                    (0, true) => 0o755,
+                    (0, false) => 0o644,
+                    (mode, _) => mode,
+                });
+
+                match (node.dir, &node.link) {
+                    (true, _) => {
+                        header.set_entry_type(EntryType::Directory);
+                        header.set_size(0);
+
+                        builder
+                            .append_data(&mut header, &node.path, io::empty())
+                            .str_result()?;
+                    }
+                    (false, Some(link)) => {
+                        header.set_entry_type(EntryType::Symlink);
+                        header.set_size(0);
+
+                        builder
+                            .append_link(&mut header, &node.path, link)
+                            .str_result()?;
+                    }
+                    (false, None) => {
+                        header.set_entry_type(EntryType::Regular);
+                        header.set_size(node.size);
+
+                        builder
+                            .append_data(
+                                &mut header,
+                                &node.path,
+                                File::open(self.blob_path(&node.sha256)).str_result()?,
+                            )
+                            .str_result()?;
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn verify(&self, tree: &str) -> Result<(), String> {
+        for node in self.load(tree)?.entries {
+            if node.dir || node.link.is_some() {
+                continue;
+            }
+
+            match digest(&self.blob_path(&node.sha256).to_string_lossy()) {
+                Ok((sha256, size)) if sha256.eq(&node.sha256) && size == node.size => {}
+                _ => return Err(format!("{}: blob is missing or corrupted!", node.path)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // removes every blob none of `trees` reaches, returns how many and their size.
+    // the trees are only listed once no import is running, so none can be missed
+    pub fn gc(
+        &self,
+        trees: impl FnOnce() -> Result<Vec<String>, String>,
+    ) -> Result<(usize, u64), String> {
+        // this process doesn't import while collecting, its own hold would only be in the way
+        HELD.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(root, _)| !root.eq(&self.root));
+
+        let lock = self.lock_file()?;
+
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err("An import is still running, try again later!".to_string())
+            }
+            Err(TryLockError::Error(e)) => return Err(e.to_string()),
+        }
+
+        let mut referenced: HashSet<String> = HashSet::new();
+
+        // a tree that can't be read aborts, rather than losing what it refers to
+        for tree in &trees()? {
+            for node in self.load(tree)?.entries {
+                referenced.insert(node.sha256);
+            }
+
+            referenced.insert(tree.clone());
+        }
+
+        let (mut removed, mut freed) = (0, 0);
+
+        // blobs being written right now are left to the import writing them
+        for file in list(&self.root.join("incoming"))? {
+            let metadata = file.metadata().str_result()?;
+
+            if metadata
+                .modified()
+                .str_result()?
+                .elapsed()
+                .unwrap_or_default()
+                > STALE
+            {
+                remove_file(file.path()).str_result()?;
+            }
+        }
+
+        for prefix in list(&self.root.join("blobs"))? {
+            let prefix = prefix.path();
+
+            for blob in fs::read_dir(&prefix).str_result()? {
+                let blob = blob.str_result()?;
+
+                if referenced.contains(blob.file_name().to_string_lossy().as_ref()) {
+                    continue;
+                }
+
+                freed += blob.metadata().str_result()?.len();
+                removed += 1;
+
+                remove_file(blob.path()).str_result()?;
+            }
+
+            // only succeeds once the prefix is empty
+            remove_dir(&prefix).ok();
+        }
+
+        Ok((removed, freed))
+    }
+
+    // streams into the store while hashing, the blob is only kept when it is new.
+    // every import writes its own incoming file, so several can run at once
+    fn put(&self, reader: impl Read) -> Result<(String, u64, bool), String> {
+        self.hold()?;
+
+        let incoming = self.root.join("incoming");
+        create_dir_all(&incoming).str_result()?;
+
+        let incoming = incoming.join(format!(
+            "{}-{}",
+            process::id(),
+            INCOMING.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&incoming)
+            .str_result()?;
+
+        let result = stream(reader, &mut file).and_then(|hashed| {
+            file.sync_all().str_result()?;
+
+            Ok(hashed)
+        });
+        drop(file);
+
+        let (sha256, size) = match result {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                remove_file(&incoming).ok();
+
+                return Err(e);
+            }
+        };
+
+        let blob = self.blob_path(&sha256);
+
+        if blob.exists() {
+            remove_file(&incoming).str_result()?;
+
+            return Ok((sha256, size, false));
+        }
+
+        create_dir_all(blob.parent().unwrap()).str_result()?;
+        rename(&incoming, &blob).str_result()?;
+
+        Ok((sha256, size, true))
+    }
+
+    fn hold(&self) -> Result<(), String> {
+        let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+
+        if held.iter().any(|(root, _)| root.eq(&self.root)) {
+            return Ok(());
+        }
+
+        let lock = self.lock_file()?;
+        lock.lock_shared().str_result()?;
+
+        held.push((self.root.clone(), lock));
+
+        Ok(())
+    }
+
+    fn lock_file(&self) -> Result<File, String> {
+        create_dir_all(&self.root).str_result()?;
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.root.join("lock"))
+            .str_result()
+    }
+
+    fn write_tree(&self, entries: Vec<Node>) -> Result<String, String> {
+        let tree = toml::to_string(&Tree { entries }).str_result()?;
+
+        let (sha256, _, _) = self.put(tree.as_bytes())?;
+
+        Ok(sha256)
+    }
+}
+
+// copies while hashing, returns the sha-256 and size of what went through
+fn stream(mut reader: impl Read, writer: &mut impl Write) -> Result<(String, u64), String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size: u64 = 0;
+
+    loop {
+        let read = reader.read(&mut buffer).str_result()?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read]).str_result()?;
+        size += read as u64;
+    }
+
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok((sha256, size))
+}
+
+// nothing there yet is as good as empty
+fn list(dir: &Path) -> Result<Vec<fs::DirEntry>, String> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|e| e.str_result()).collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[inline(always)]
+fn report() -> Report {
+    Report {
+        mode: Mode::Store,
+        rewritten: 0,
+        rewritten_bytes: 0,
+        reused: 0,
+        reused_bytes: 0,
+        removed: 0,
+    }
+}
+
+// new blobs count as rewritten, deduplicated ones as reused
+#[inline(always)]
+fn count(report: &mut Report, size: u64, new: bool) {
+    match new {
+        true => {
+            report.rewritten += 1;
+            report.rewritten_bytes += size;
+        }
+        false => {
+            report.reused += 1;
+            report.reused_bytes += size;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn mode(path: &Path) -> Result<u32, String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(fs::metadata(path).str_result()?.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_path: &Path) -> Result<u32, String> {
+    Ok(0)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // trees written on windows carry no mode
+    if mode == 0 {
+        return Ok(());
+    }
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).str_result()
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(unix)]
+#[inline(always)]
+fn symlink(link: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(link, path)
+}
+
+#[cfg(windows)]
+#[inline(always)]
+fn symlink(link: &str, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(link, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{read_to_string, write},
+        thread,
+        time::SystemTime,
+    };
+
+    use tar::Builder;
+
+    use super::*;
+    use crate::testing::Scratch;
+
+    fn store(scratch: &Scratch) -> Store {
+        Store::new(scratch.root.join("store"))
+    }
+
+    fn blobs(store: &Store) -> usize {
+        list(&store.root.join("blobs"))
+            .unwrap()
+            .iter()
+            .map(|prefix| list(&prefix.path()).unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn identical_files_share_a_blob() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("lab/a.txt", "same");
+        scratch.write("lab/sub/b.txt", "same");
+        scratch.write("lab/c.txt", "other");
+
+        let (_, report) = store.import_dir(&scratch.path("lab"), None).unwrap();
+
+        assert_eq!((report.rewritten, report.reused), (2, 1));
+        // two files and the tree
+        assert_eq!(blobs(&store), 3);
+    }
+
+    #[test]
+    fn checkout_brings_the_folder_back() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("lab/a.txt", "first");
+        scratch.write("lab/sub/b.txt", "second");
+
+        let (tree, _) = store.import_dir(&scratch.path("lab"), None).unwrap();
+
+        let target = scratch.root.join("out");
+        store
+            .checkout(&store.load(&tree).unwrap(), &target)
+            .unwrap();
+
+        assert_eq!(read_to_string(target.join("a.txt")).unwrap(), "first");
+        assert_eq!(read_to_string(target.join("sub/b.txt")).unwrap(), "second");
+        store.verify(&tree).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folders_keep_their_links() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("lab/a.txt", "first");
+        symlink("a.txt", &scratch.root.join("lab/b.txt")).unwrap();
+        symlink("/nowhere", &scratch.root.join("lab/dangling")).unwrap();
+
+        let (tree, _) = store.import_dir(&scratch.path("lab"), None).unwrap();
+
+        let target = scratch.root.join("out");
+        store
+            .checkout(&store.load(&tree).unwrap(), &target)
+            .unwrap();
+
+        assert_eq!(
+            fs::read_link(target.join("b.txt")).unwrap(),
+            Path::new("a.txt")
+        );
+        assert_eq!(
+            fs::read_link(target.join("dangling")).unwrap(),
+            Path::new("/nowhere")
+        );
+        store.verify(&tree).unwrap();
+    }
+
+    #[test]
+    fn corrupted_blobs_are_not_checked_out() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("lab/a.txt", "first");
+
+        let (tree, _) = store.import_dir(&scratch.path("lab"), None).unwrap();
+        let tree = store.load(&tree).unwrap();
+
+        let node = tree.entries.iter().find(|n| n.path.eq("a.txt")).unwrap();
+        write(store.blob_path(&node.sha256), "tampered").unwrap();
+
+        let target = scratch.root.join("out");
+
+        assert!(store.checkout(&tree, &target).is_err());
+        assert!(!target.join("a.txt").exists());
+    }
+
+    #[test]
+    fn reimport_counts_what_went_away() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("lab/a.txt", "first");
+        scratch.write("lab/b.txt", "second");
+        scratch.write("lab/c.txt", "third");
+
+        let (tree, _) = store.import_dir(&scratch.path("lab"), None).unwrap();
+
+        fs::remove_file(scratch.path("lab/b.txt")).unwrap();
+        fs::remove_file(scratch.path("lab/c.txt")).unwrap();
+        scratch.write("lab/d.txt", "fourth");
+
+        let (_, report) = store.import_dir(&scratch.path("lab"), Some(&tree)).unwrap();
+
+        assert_eq!(report.removed, 2);
+        assert_eq!((report.rewritten, report.reused), (1, 1));
+    }
+
+    #[test]
+    fn imports_can_run_side_by_side() {
+        let scratch = Scratch::new();
+        let root = scratch.root.join("store");
+
+        let imports: Vec<_> = (0..8)
+            .map(|i| {
+                let root = root.clone();
+
+                thread::spawn(move || {
+                    let content = format!("content {}", i).repeat(10_000);
+
+                    Store::new(root).put(content.as_bytes()).unwrap()
+                })
+            })
+            .collect();
+
+        let store = Store::new(root);
+
+        for import in imports {
+            let (sha256, _, _) = import.join().unwrap();
+
+            assert!(store.blob_path(&sha256).exists());
+        }
+
+        assert!(list(&store.root.join("incoming")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn gc_keeps_referenced_blobs_and_fresh_incoming_files() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("keep/a.txt", "kept");
+        scratch.write("drop/b.txt", "dropped");
+
+        let (kept, _) = store.import_dir(&scratch.path("keep"), None).unwrap();
+        store.import_dir(&scratch.path("drop"), None).unwrap();
+
+        let incoming = store.root.join("incoming");
+        write(incoming.join("fresh"), "being written").unwrap();
+        write(incoming.join("stale"), "left behind").unwrap();
+
+        OpenOptions::new()
+            .write(true)
+            .open(incoming.join("stale"))
+            .unwrap()
+            .set_modified(SystemTime::now() - STALE * 2)
+            .unwrap();
+
+        let (removed, _) = store.gc(|| Ok(vec![kept.clone()])).unwrap();
+
+        assert_eq!(removed, 2);
+        store.verify(&kept).unwrap();
+        assert!(incoming.join("fresh").exists());
+        assert!(!incoming.join("stale").exists());
+    }
+
+    // `a -> b` makes a symlink, `a => b` a hardlink, anything else a file with content
+    fn archive(entries: &[&str]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(0);
+
+            if let Some((path, target)) = entry.split_once(" -> ") {
+                header.set_entry_type(EntryType::Symlink);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else if let Some((path, target)) = entry.split_once(" => ") {
+                header.set_entry_type(EntryType::Link);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else {
+                let (path, content) = entry.split_once('=').unwrap();
+
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(content.len() as u64);
+                builder
+                    .append_data(&mut header, path, content.as_bytes())
+                    .unwrap();
+            }
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn archives_keep_the_last_copy_and_their_links() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        let archive = archive(&["a.txt=old", "a.txt=new", "link -> a.txt", "hard => a.txt"]);
+        let (tree, report) = store.import_archive(archive.as_slice()).unwrap();
+
+        assert_eq!(blobs(&store), 3);
+        assert_eq!(report.reused, 1);
+        store.verify(&tree).unwrap();
+
+        let target = scratch.root.join("out");
+        store
+            .checkout(&store.load(&tree).unwrap(), &target)
+            .unwrap();
+
+        assert_eq!(read_to_string(target.join("a.txt")).unwrap(), "new");
+        assert_eq!(read_to_string(target.join("hard")).unwrap(), "new");
+        assert_eq!(
+            fs::read_link(target.join("link")).unwrap(),
+            Path::new("a.txt")
+        );
+
+        // and back into an image, links included
+        let image = scratch.path("image.tar");
+        store.write_image(&tree, &image).unwrap();
+
+        let (again, _) = store.import_archive(File::open(&image).unwrap()).unwrap();
+
+        assert_eq!(again, tree);
+    }
+
+    #[test]
+    fn nothing_is_written_through_a_link() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+        let outside = scratch.path("outside");
+
+        let archive = archive(&[&format!("link -> {}", outside), "link/victim=written"]);
+        let (tree, _) = store.import_archive(archive.as_slice()).unwrap();
+
+        fs::create_dir_all(&outside).unwrap();
+        let target = scratch.root.join("out");
+
+        assert!(store
+            .checkout(&store.load(&tree).unwrap(), &target)
+            .is_err());
+        assert!(!Path::new(&outside).join("victim").exists());
+    }
+
+    #[test]
+    fn hardlinks_need_their_target() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        let archive = archive(&["hard => missing"]);
+
+        assert!(store.import_archive(archive.as_slice()).is_err());
+    }
+
+    #[test]
+    fn gc_waits_for_running_imports() {
+        let scratch = Scratch::new();
+        let store = store(&scratch);
+
+        scratch.write("lab/a.txt", "not in the cache yet");
+        store.import_dir(&scratch.path("lab"), None).unwrap();
+
+        // another process still importing
+        let import = store.lock_file().unwrap();
+        import.lock_shared().unwrap();
+
+        assert!(store.gc(|| Ok(Vec::new())).is_err());
+        assert_eq!(blobs(&store), 2);
+
+        drop(import);
+
+        let (removed, _) = store.gc(|| Ok(Vec::new())).unwrap();
+
+        assert_eq!(removed, 2);
+    }
+}
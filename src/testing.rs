@@ -0,0 +1,48 @@
+use std::{
+    env,
+    fs::{create_dir_all, remove_dir_all, write},
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// a fresh folder for one test, removed again when dropped
+pub struct Scratch {
+    pub root: PathBuf,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        let root = env::temp_dir().join(format!(
+            "laboratory-test-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        create_dir_all(&root).unwrap();
+
+        Self { root }
+    }
+
+    #[inline(always)]
+    pub fn path(&self, name: &str) -> String {
+        self.root.join(name).to_string_lossy().to_string()
+    }
+
+    pub fn write(&self, name: &str, content: &str) -> String {
+        let path = self.root.join(name);
+
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, content).unwrap();
+
+        path.to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        remove_dir_all(&self.root).ok();
+    }
+}
@@ -0,0 +1,896 @@
+use std::{fs::OpenOptions, io::Read, ops::Range};
+
+use toml::{Table, Value};
+use toml_edit::{ImDocument, Item};
+
+use crate::{
+    format::{located, position, Format},
+    image::{Env, LabConfig, ParamKind},
+    include,
+};
+
+const PLATFORMS: [&str; 10] = [
+    "linux",
+    "windows",
+    "macos",
+    "ios",
+    "android",
+    "freebsd",
+    "dragonfly",
+    "netbsd",
+    "openbsd",
+    "solaris",
+];
+
+#[derive(Clone)]
+enum Segment {
+    Key(&'static str),
+    Entry(String),
+    Index(usize),
+}
+
+use Segment::*;
+
+struct Problem {
+    at: Vec<Segment>,
+    message: String,
+}
+
+pub fn check_file(path: &str) -> Result<LabConfig, Vec<String>> {
+    let mut source = String::new();
+
+    if let Err(e) = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+    {
+        return Err(vec![format!("{}: {}", path, e)]);
+    }
+
+    check(path, &source)
+}
+
+pub fn check(path: &str, source: &str) -> Result<LabConfig, Vec<String>> {
+    let format = Format::detect(path, source);
+
+    let table = format.parse_table(path, source).map_err(|e| vec![e])?;
+
+    if include::is_composed(&table) {
+        return check_composed(path, table);
+    }
+
+    // the direct parse keeps positions, the table only differs by the nulls stripped from it
+    let config: LabConfig = match format.parse(path, source) {
+        Ok(config) => config,
+        Err(e) => Value::Table(table).try_into().map_err(|_| vec![e])?,
+    };
+
+    let problems = problems(&config);
+
+    if problems.is_empty() {
+        return Ok(config);
+    }
+
+    let document = match format {
+        Format::Toml => ImDocument::parse(source).ok(),
+        _ => None,
+    };
+
+    Err(problems
+        .iter()
+        .map(|p| {
+            let offset = match format {
+                Format::Toml => document
+                    .as_ref()
+                    .and_then(|d| locate(d.as_item(), &p.at))
+                    .map(|s| s.start),
+                Format::Json => locate_json(source, &p.at),
+                Format::Yaml => locate_yaml(source, &p.at),
+            };
+
+            located(path, offset.map(|o| position(source, o)), &p.message)
+        })
+        .collect())
+}
+
+// merged configs have no single source to point into, so problems carry no position
+fn check_composed(path: &str, table: Table) -> Result<LabConfig, Vec<String>> {
+    let merged = include::resolve(path, table).map_err(|e| vec![located(path, None, &e)])?;
+
+    let config: LabConfig = Value::Table(merged)
+        .try_into()
+        .map_err(|e: toml::de::Error| vec![located(path, None, e.message())])?;
+
+    match check_config(&config) {
+        Ok(_) => Ok(config),
+        Err(problems) => Err(problems.iter().map(|p| located(path, None, p)).collect()),
+    }
+}
+
+pub fn check_config(config: &LabConfig) -> Result<(), Vec<String>> {
+    let problems = problems(config);
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(problems.into_iter().map(|p| p.message).collect())
+}
+
+fn problems(config: &LabConfig) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let mut report = |at: Vec<Segment>, message: String| problems.push(Problem { at, message });
+
+    if config.name.trim().is_empty() {
+        report(vec![Key("name")], "Lab name is empty".to_string());
+    }
+
+    for (i, env) in config.envs.iter().enumerate() {
+        if env.key.trim().is_empty() || env.key.contains('=') {
+            report(
+                vec![Key("envs"), Index(i), Key("key")],
+                format!("Invalid env key: {:?}", env.key),
+            );
+        }
+
+        for var in unknown_vars(&env.value, config) {
+            report(
+                vec![Key("envs"), Index(i), Key("value")],
+                format!("Unknown variable: {}", var),
+            );
+        }
+    }
+
+    let mut names: Vec<&str> = Vec::new();
+
+    for (i, app) in config.apps.iter().enumerate() {
+        if app.name.trim().is_empty() {
+            report(
+                vec![Key("apps"), Index(i), Key("name")],
+                "App name is empty".to_string(),
+            );
+        }
+
+        if names.contains(&app.name.as_str()) {
+            report(
+                vec![Key("apps"), Index(i), Key("name")],
+                format!("Duplicate app name: {}", app.name),
+            );
+        }
+        names.push(&app.name);
+
+        for (j, alias) in app.aliases.iter().enumerate() {
+            if names.contains(&alias.as_str()) {
+                report(
+                    vec![Key("apps"), Index(i), Key("aliases"), Index(j)],
+                    format!("Alias clashes with another app: {}", alias),
+                );
+            }
+            names.push(alias);
+        }
+
+        if app.command.trim().is_empty()
+            && (app.target.is_empty() || app.target.values().any(|t| t.command.is_none()))
+        {
+            report(
+                vec![Key("apps"), Index(i), Key("command")],
+                "Command is empty".to_string(),
+            );
+        }
+
+        let mut paths = vec![
+            (vec![Key("apps"), Index(i), Key("command")], &app.command),
+            (vec![Key("apps"), Index(i), Key("work_dir")], &app.work_dir),
+        ];
+        let mut values = paths.clone();
+        let mut envs: Vec<(Vec<Segment>, &Env)> = Vec::new();
+
+        for (j, arg) in app.args.iter().enumerate() {
+            values.push((vec![Key("apps"), Index(i), Key("args"), Index(j)], arg));
+        }
+        for (j, env) in app.envs.iter().enumerate() {
+            envs.push((vec![Key("apps"), Index(i), Key("envs"), Index(j)], env));
+        }
+
+        for (os, target) in &app.target {
+            let at = |rest: Vec<Segment>| {
+                let mut at = vec![Key("apps"), Index(i), Key("target"), Entry(os.clone())];
+                at.extend(rest);
+
+                at
+            };
+
+            if !PLATFORMS.contains(&os.as_str()) {
+                report(at(vec![]), format!("Unknown target platform: {}", os));
+            }
+
+            if let Some(command) = &target.command {
+                paths.push((at(vec![Key("command")]), command));
+            }
+            if let Some(work_dir) = &target.work_dir {
+                paths.push((at(vec![Key("work_dir")]), work_dir));
+            }
+            for (j, arg) in target.args.iter().flatten().enumerate() {
+                values.push((at(vec![Key("args"), Index(j)]), arg));
+            }
+            for (j, env) in target.envs.iter().enumerate() {
+                envs.push((at(vec![Key("envs"), Index(j)]), env));
+            }
+        }
+
+        for (at, path) in paths {
+            let path = substitute_vars(path, config);
+
+            if path.trim().is_empty() {
+                continue;
+            }
+
+            if let Some(message) = lab_path_problem(&path) {
+                report(at, message);
+            }
+        }
+
+        for (at, env) in envs {
+            if env.key.trim().is_empty() || env.key.contains('=') {
+                let mut at_key = at.clone();
+                at_key.push(Key("key"));
+
+                report(at_key, format!("Invalid env key: {:?}", env.key));
+            }
+
+            let mut at_value = at;
+            at_value.push(Key("value"));
+
+            values.push((at_value, &env.value));
+        }
+
+        for (at, value) in values {
+            for var in unknown_vars(value, config) {
+                report(at.clone(), format!("Unknown variable: {}", var));
+            }
+        }
+
+        for (j, param) in app.params.iter().enumerate() {
+            let at = |key| vec![Key("apps"), Index(i), Key("params"), Index(j), Key(key)];
+
+            if app.params[..j].iter().any(|p| p.name.eq(&param.name)) {
+                report(at("name"), format!("Duplicate parameter: {}", param.name));
+            }
+
+            if param.kind == ParamKind::Enum && param.values.is_empty() {
+                report(at("values"), "Enum parameter has no values".to_string());
+            }
+
+            if let Some(default) = &param.default {
+                let valid = match param.kind {
+                    ParamKind::Int => default.parse::<i64>().is_ok(),
+                    ParamKind::Enum => param.values.contains(default),
+                    _ => true,
+                };
+
+                if !valid {
+                    report(
+                        at("default"),
+                        format!(
+                            "Default is not a valid {}: {}",
+                            param.kind.as_str(),
+                            default
+                        ),
+                    );
+                }
+            }
+        }
+
+        if let Some(sandbox) = &app.sandbox {
+            let mount_point = sandbox.mount_point.trim_matches('/');
+
+            // `/` puts the lab itself at the root
+            if sandbox.mount_point.is_empty()
+                || mount_point
+                    .split_terminator('/')
+                    .any(|c| c.is_empty() || c.eq(".."))
+                || mount_point.split_terminator('/').next() == Some("proc")
+            {
+                report(
+                    vec![Key("apps"), Index(i), Key("sandbox"), Key("mount_point")],
+                    format!("Invalid sandbox mount point: {}", sandbox.mount_point),
+                );
+            }
+        }
+    }
+
+    if let Some(default_app) = &config.default_app {
+        if !names.contains(&default_app.as_str()) {
+            report(
+                vec![Key("default_app")],
+                format!("Unknown default app: {}", default_app),
+            );
+        }
+    }
+
+    for (i, group) in config.groups.iter().enumerate() {
+        if config.groups[..i].iter().any(|g| g.name.eq(&group.name)) {
+            report(
+                vec![Key("groups"), Index(i), Key("name")],
+                format!("Duplicate group name: {}", group.name),
+            );
+        }
+
+        if let Some(main) = &group.main {
+            if !group.members.iter().any(|m| m.app.eq(main)) {
+                report(
+                    vec![Key("groups"), Index(i), Key("main")],
+                    format!("Main app is not a group member: {}", main),
+                );
+            }
+        }
+
+        for (j, member) in group.members.iter().enumerate() {
+            if !names.contains(&member.app.as_str()) {
+                report(
+                    vec![
+                        Key("groups"),
+                        Index(i),
+                        Key("members"),
+                        Index(j),
+                        Key("app"),
+                    ],
+                    format!("Unknown app: {}", member.app),
+                );
+            }
+
+            for (k, dependency) in member.depends_on.iter().enumerate() {
+                if !group.members.iter().any(|m| m.app.eq(dependency)) {
+                    report(
+                        vec![
+                            Key("groups"),
+                            Index(i),
+                            Key("members"),
+                            Index(j),
+                            Key("depends_on"),
+                            Index(k),
+                        ],
+                        format!("Unknown dependency: {}", dependency),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(default_profile) = &config.default_profile {
+        if !config.profiles.contains_key(default_profile) {
+            report(
+                vec![Key("default_profile")],
+                format!("Unknown default profile: {}", default_profile),
+            );
+        }
+    }
+
+    for (name, profile) in &config.profiles {
+        let at = |rest: Vec<Segment>| {
+            let mut at = vec![Key("profiles"), Entry(name.clone())];
+            at.extend(rest);
+
+            at
+        };
+
+        let mut envs: Vec<(Vec<Segment>, &Env)> = Vec::new();
+
+        for (j, env) in profile.envs.iter().enumerate() {
+            envs.push((at(vec![Key("envs"), Index(j)]), env));
+        }
+
+        for (app, overlay) in &profile.apps {
+            if !config.apps.iter().any(|a| a.name.eq(app)) {
+                report(
+                    at(vec![Key("apps"), Entry(app.clone())]),
+                    format!("Unknown app: {}", app),
+                );
+            }
+
+            for (j, arg) in overlay.args.iter().flatten().enumerate() {
+                for var in unknown_vars(arg, config) {
+                    report(
+                        at(vec![Key("apps"), Entry(app.clone()), Key("args"), Index(j)]),
+                        format!("Unknown variable: {}", var),
+                    );
+                }
+            }
+
+            for (j, env) in overlay.envs.iter().enumerate() {
+                envs.push((
+                    at(vec![Key("apps"), Entry(app.clone()), Key("envs"), Index(j)]),
+                    env,
+                ));
+            }
+        }
+
+        for (at, env) in envs {
+            if env.key.trim().is_empty() || env.key.contains('=') {
+                let mut at_key = at.clone();
+                at_key.push(Key("key"));
+
+                report(at_key, format!("Invalid env key: {:?}", env.key));
+            }
+
+            for var in unknown_vars(&env.value, config) {
+                let mut at_value = at.clone();
+                at_value.push(Key("value"));
+
+                report(at_value, format!("Unknown variable: {}", var));
+            }
+        }
+    }
+
+    if let Some(requires) = &config.requires {
+        let lists = [
+            ("executables", &requires.executables),
+            ("files", &requires.files),
+            ("envs", &requires.envs),
+        ];
+
+        for (key, list) in lists {
+            for (i, entry) in list.iter().enumerate() {
+                if entry.trim().is_empty() {
+                    report(
+                        vec![Key("requires"), Key(key), Index(i)],
+                        "Required entry is empty".to_string(),
+                    );
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+// configs written before these were checked keep working, they are only pointed out
+pub fn warnings(config: &LabConfig) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for app in config.apps.iter().filter(|a| a.sandbox.is_none()) {
+        let mut paths = vec![&app.command, &app.work_dir];
+
+        for target in app.target.values() {
+            paths.extend(&target.command);
+            paths.extend(&target.work_dir);
+        }
+
+        for path in paths {
+            let path = substitute_vars(path, config);
+
+            if !path.trim().is_empty() && !path.starts_with(['\\', '/']) {
+                warnings.push(format!(
+                    "App {}: path does not start at the lab root (\\ or /): {}",
+                    app.name, path
+                ));
+            }
+        }
+    }
+
+    warnings
+}
+
+fn substitute_vars(value: &str, config: &LabConfig) -> String {
+    let mut value = value.to_string();
+
+    for (key, var) in &config.vars {
+        value = value.replace(&format!("$var:{}$", key), var);
+    }
+
+    value
+}
+
+fn unknown_vars<'a>(value: &'a str, config: &LabConfig) -> Vec<&'a str> {
+    let mut unknown = Vec::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("$var:") {
+        rest = &rest[start + "$var:".len()..];
+
+        match rest.find('$') {
+            Some(end) => {
+                if !config.vars.contains_key(&rest[..end]) {
+                    unknown.push(&rest[..end]);
+                }
+
+                rest = &rest[end + 1..];
+            }
+            None => break,
+        }
+    }
+
+    unknown
+}
+
+// commands and working directories are joined onto the mount, so they must stay inside the lab
+fn lab_path_problem(path: &str) -> Option<String> {
+    if path.contains(':') {
+        return Some(format!("Path must be relative to the lab root: {}", path));
+    }
+
+    if path.split(['\\', '/']).any(|c| c.eq("..")) {
+        return Some(format!("Path escapes the lab root: {}", path));
+    }
+
+    None
+}
+
+fn locate(item: &Item, at: &[Segment]) -> Option<Range<usize>> {
+    let mut item = item;
+    let mut span = item.span();
+
+    for segment in at {
+        let next = match segment {
+            Key(key) => item.get(*key),
+            Entry(key) => item.get(key.as_str()),
+            Index(index) => item.get(*index),
+        };
+
+        match next {
+            Some(next) => {
+                item = next;
+                span = item.span().or(span);
+            }
+            None => break,
+        }
+    }
+
+    span
+}
+
+// json keeps no spans once parsed, so the source is walked along the path instead
+fn locate_json(source: &str, at: &[Segment]) -> Option<usize> {
+    let bytes = source.as_bytes();
+    let mut offset = skip_space(bytes, 0);
+    let mut found = (offset < bytes.len()).then_some(offset);
+
+    for segment in at {
+        let next = match (segment, bytes.get(offset)) {
+            (Key(key), Some(b'{')) => json_member(source, offset, key),
+            (Entry(key), Some(b'{')) => json_member(source, offset, key),
+            (Index(index), Some(b'[')) => json_element(bytes, offset, *index),
+            _ => None,
+        };
+
+        match next {
+            Some(next) => {
+                offset = next;
+                found = Some(next);
+            }
+            None => break,
+        }
+    }
+
+    found
+}
+
+fn json_member(source: &str, object: usize, key: &str) -> Option<usize> {
+    let bytes = source.as_bytes();
+    let mut i = skip_space(bytes, object + 1);
+
+    loop {
+        if bytes.get(i)? != &b'"' {
+            return None;
+        }
+
+        let end = skip_string(bytes, i)?;
+        let name: String = serde_json::from_str(&source[i..end]).ok()?;
+
+        i = skip_space(bytes, end);
+        if bytes.get(i)? != &b':' {
+            return None;
+        }
+
+        let value = skip_space(bytes, i + 1);
+        if name.eq(key) {
+            return Some(value);
+        }
+
+        i = skip_space(bytes, skip_value(bytes, value)?);
+        if bytes.get(i)? != &b',' {
+            return None;
+        }
+
+        i = skip_space(bytes, i + 1);
+    }
+}
+
+fn json_element(bytes: &[u8], array: usize, index: usize) -> Option<usize> {
+    let mut i = skip_space(bytes, array + 1);
+
+    for _ in 0..index {
+        i = skip_space(bytes, skip_value(bytes, i)?);
+        if bytes.get(i)? != &b',' {
+            return None;
+        }
+
+        i = skip_space(bytes, i + 1);
+    }
+
+    match bytes.get(i)? {
+        b']' => None,
+        _ => Some(i),
+    }
+}
+
+fn skip_space(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+
+    i
+}
+
+fn skip_string(bytes: &[u8], mut i: usize) -> Option<usize> {
+    i += 1;
+
+    loop {
+        match bytes.get(i)? {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+fn skip_value(bytes: &[u8], mut i: usize) -> Option<usize> {
+    match bytes.get(i)? {
+        b'"' => skip_string(bytes, i),
+        b'{' | b'[' => {
+            let mut depth = 0;
+
+            loop {
+                match bytes.get(i)? {
+                    b'"' => {
+                        i = skip_string(bytes, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+
+                i += 1;
+            }
+        }
+        _ => {
+            while i < bytes.len() && !b",}] \t\r\n".contains(&bytes[i]) {
+                i += 1;
+            }
+
+            Some(i)
+        }
+    }
+}
+
+// only block-style yaml is followed, which is how configs get written by hand.
+// a block is a range of lines and the column its entries start at
+fn locate_yaml(source: &str, at: &[Segment]) -> Option<usize> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    for line in source.split('\n') {
+        lines.push((start, line.trim_end_matches('\r')));
+        start += line.len() + 1;
+    }
+
+    let content = |line: usize, column: usize| -> Option<&str> {
+        let text = lines[line].1;
+        let prefix = text.get(..column)?;
+
+        match prefix.chars().all(|c| c == ' ' || c == '-') && !text[column..].starts_with(' ') {
+            true => Some(&text[column..]),
+            false => None,
+        }
+    };
+    let blank = |line: usize| {
+        let text = lines[line].1.trim_start();
+        text.is_empty() || text.starts_with('#')
+    };
+    let indent = |line: usize| lines[line].1.len() - lines[line].1.trim_start().len();
+
+    let mut block = match (0..lines.len()).find(|&l| !blank(l)) {
+        Some(first) => (0..lines.len(), indent(first)),
+        None => return None,
+    };
+    let mut found = None;
+
+    for segment in at {
+        let (range, column) = block.clone();
+
+        let next = match segment {
+            Key(key) => yaml_key(range.clone(), column, key, &content),
+            Entry(key) => yaml_key(range.clone(), column, key, &content),
+            Index(index) => range
+                .clone()
+                .filter(|&l| content(l, column).is_some_and(|c| c == "-" || c.starts_with("- ")))
+                .nth(*index)
+                .map(|l| (l, range.end)),
+        };
+
+        let (line, end) = match next {
+            Some(next) => next,
+            None => break,
+        };
+
+        let text = lines[line].1;
+        found = Some(lines[line].0 + column);
+
+        block = match segment {
+            // an item's entries start right after its dash, and run until the next dash
+            Index(_) => {
+                let end = (line + 1..end)
+                    .find(|&l| content(l, column).is_some_and(|c| c.starts_with('-')))
+                    .unwrap_or(end);
+                let inline =
+                    column + 1 + text[column + 1..].len() - text[column + 1..].trim_start().len();
+
+                match text[inline..].is_empty() {
+                    false => {
+                        found = Some(lines[line].0 + inline);
+                        (line..end, inline)
+                    }
+                    true => match (line + 1..end).find(|&l| !blank(l)) {
+                        Some(first) => (line + 1..end, indent(first)),
+                        None => break,
+                    },
+                }
+            }
+            // nested entries are indented further, a list may also sit at the key's own column
+            _ => {
+                let end = (line + 1..range.end)
+                    .find(|&l| {
+                        !blank(l)
+                            && indent(l) <= column
+                            && !(indent(l) == column && lines[l].1.trim_start().starts_with('-'))
+                    })
+                    .unwrap_or(range.end);
+
+                match (line + 1..end).find(|&l| !blank(l)) {
+                    Some(first) => (line + 1..end, indent(first)),
+                    None => break,
+                }
+            }
+        };
+    }
+
+    found
+}
+
+fn yaml_key<'a>(
+    range: Range<usize>,
+    column: usize,
+    key: &str,
+    content: &impl Fn(usize, usize) -> Option<&'a str>,
+) -> Option<(usize, usize)> {
+    let quoted = [
+        format!("{}:", key),
+        format!("\"{}\":", key),
+        format!("'{}':", key),
+    ];
+
+    range
+        .clone()
+        .find(|&l| {
+            content(l, column).is_some_and(|c| {
+                quoted.iter().any(|q| {
+                    c.strip_prefix(q.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+                })
+            })
+        })
+        .map(|l| (l, range.end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+  "name": "lab",
+  "description": null,
+  "tags": null,
+  "apps": [
+    { "name": "first", "command": "\\bin\\first.exe", "sandbox": null },
+    {
+      "name": "second",
+      "command": "\\bin\\second.exe",
+      "args": ["--quiet", "$var:missing$"]
+    }
+  ]
+}"#;
+
+    const YAML: &str = "name: lab
+description: ~
+tags:
+apps:
+  - name: first
+    command: \\bin\\first.exe
+    sandbox: ~
+  - name: second
+    command: \\bin\\second.exe
+    args:
+    - --quiet
+    - $var:missing$
+";
+
+    fn rejected(path: &str, source: &str) -> Vec<String> {
+        match check(path, source) {
+            Ok(_) => panic!("{} passed", path),
+            Err(problems) => problems,
+        }
+    }
+
+    #[test]
+    fn json_nulls_are_left_out() {
+        let source = JSON.replace("$var:missing$", "--loud");
+        let config = check("lab.json", &source).unwrap();
+
+        assert!(config.description.is_none());
+        assert!(config.tags.is_empty());
+        assert!(config.apps[0].sandbox.is_none());
+    }
+
+    #[test]
+    fn yaml_nulls_are_left_out() {
+        let source = YAML.replace("$var:missing$", "--loud");
+        let config = check("lab.yaml", &source).unwrap();
+
+        assert!(config.description.is_none());
+        assert!(config.tags.is_empty());
+        assert_eq!(config.apps[1].args, ["--quiet", "--loud"]);
+    }
+
+    #[test]
+    fn problems_point_into_json() {
+        let problems = rejected("lab.json", JSON);
+
+        assert_eq!(
+            problems,
+            ["lab.json:10:27: Unknown variable: missing".to_string()]
+        );
+    }
+
+    #[test]
+    fn problems_point_into_yaml() {
+        let problems = rejected("lab.yaml", YAML);
+
+        assert_eq!(
+            problems,
+            ["lab.yaml:12:7: Unknown variable: missing".to_string()]
+        );
+    }
+
+    #[test]
+    fn problems_point_into_toml() {
+        let source = "name = \"lab\"\n\n[[apps]]\nname = \"first\"\ncommand = \"\"\n";
+        let problems = rejected("lab.toml", source);
+
+        assert_eq!(problems, ["lab.toml:5:11: Command is empty".to_string()]);
+    }
+
+    #[test]
+    fn type_errors_keep_their_position() {
+        let source = JSON.replace("\"tags\": null", "\"tags\": 3");
+        let problems = rejected("lab.json", &source);
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("lab.json:4:"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn locators_stop_at_the_deepest_match() {
+        let at = [Key("apps"), Index(5), Key("name")];
+
+        assert_eq!(locate_json(JSON, &at), JSON.find('['));
+        assert_eq!(locate_yaml(YAML, &at), YAML.find("apps"));
+    }
+}